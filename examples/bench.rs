@@ -12,14 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use qwal::{Result, Wal};
+use qwal::{Options, Result, SyncPolicy, Wal};
 use std::time;
 
 use tempfile::Builder as TempDirBuilder;
-async fn bench_read_write(n: u32, size: usize) -> Result<()> {
+async fn bench_read_write(n: u32, size: usize, sync: SyncPolicy) -> Result<()> {
     let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
 
-    let mut wal = Wal::open(temp_dir.path(), 2048, 100).await?;
+    let mut options = Options::new(2048, 100);
+    options.sync = sync;
+    let mut wal = Wal::open_with(temp_dir.path(), options).await?;
 
     let data = vec![0u8; size];
     for _ in 0..n {
@@ -37,17 +39,26 @@ const RUNS: u32 = 10000;
 #[cfg_attr(feature = "tokio", tokio::main)]
 async fn main() {
     let sizes = vec![0, 128, 1024, 1024 * 10, 1024 * 100];
-    for size in sizes {
-        let start = time::Instant::now();
-        bench_read_write(RUNS, size)
-            .await
-            .expect("bench_read_write");
-        let e = start.elapsed();
-        println!(
-            "bench_read_write@{:7}bytes took {:?} ({:?} per entry)",
-            size,
-            e,
-            e / RUNS
-        );
+    let policies = vec![
+        SyncPolicy::Always,
+        SyncPolicy::Entries(100),
+        SyncPolicy::Interval(time::Duration::from_millis(10)),
+        SyncPolicy::Never,
+    ];
+    for sync in policies {
+        for size in &sizes {
+            let start = time::Instant::now();
+            bench_read_write(RUNS, *size, sync)
+                .await
+                .expect("bench_read_write");
+            let e = start.elapsed();
+            println!(
+                "bench_read_write@{:7}bytes {:?} took {:?} ({:?} per entry)",
+                size,
+                sync,
+                e,
+                e / RUNS
+            );
+        }
     }
 }
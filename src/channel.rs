@@ -0,0 +1,276 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Entry, Error, Options, Result, Wal, WalConsumer, WalProducer};
+#[cfg(feature = "async-std")]
+use async_std::path::Path;
+#[cfg(feature = "tokio")]
+use std::path::Path;
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Creates a disk backed multi-producer, single-consumer channel that stores its messages in
+/// the WAL in `dir`.
+///
+/// Messages that were sent but not acknowledged are received again after a restart. As with
+/// [`Wal::close`] acknowledgements are persisted with the next send, use
+/// [`Receiver::preserve_ack`] to persist them on shutdown.
+///
+/// The channel closes like the std and tokio channels: once all [`Sender`]s are dropped or the
+/// [`Receiver`] is closed [`Receiver::recv`] returns the remaining messages and then `None`,
+/// once the [`Receiver`] is closed or dropped [`Sender::send`] fails with [`Error::Closed`].
+///
+/// ## Errors
+/// If the WAL can not be opened, see [`Wal::open_with`]
+pub async fn channel<E, P>(dir: P, options: Options) -> Result<(Sender<E>, Receiver<E>)>
+where
+    E: Entry,
+    P: AsRef<Path>,
+{
+    let (producer, consumer) = Wal::open_with(dir, options).await?.split();
+    let state = Arc::new(State {
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    let tx = Sender {
+        producer,
+        state: state.clone(),
+        entry: PhantomData,
+    };
+    let rx = Receiver {
+        consumer,
+        state,
+        entry: PhantomData,
+    };
+    Ok((tx, rx))
+}
+
+struct State {
+    /// Number of senders that are alive
+    senders: AtomicUsize,
+    /// The receiver is closed or dropped
+    closed: AtomicBool,
+}
+
+/// Sends messages to a [`channel`], can be cloned to send from multiple tasks.
+pub struct Sender<E> {
+    producer: WalProducer,
+    state: Arc<State>,
+    entry: PhantomData<fn(E)>,
+}
+
+impl<E> Sender<E>
+where
+    E: Entry,
+{
+    /// Sends a message, returns once the message is written to the WAL and synced according
+    /// to its [`SyncPolicy`](crate::SyncPolicy), see [`Wal::push`].
+    ///
+    /// ## Errors
+    /// If the receiver is closed, on IO Errors or if the message exceeds the WAL's capacity
+    pub async fn send(&self, data: E) -> std::result::Result<u64, Error<E::Error>> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        self.producer.push(data).await
+    }
+
+    /// The receiver is closed or dropped
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.state.closed.load(Ordering::Acquire)
+    }
+}
+
+impl<E> Clone for Sender<E> {
+    fn clone(&self) -> Self {
+        self.state.senders.fetch_add(1, Ordering::AcqRel);
+        Self {
+            producer: self.producer.clone(),
+            state: self.state.clone(),
+            entry: PhantomData,
+        }
+    }
+}
+
+impl<E> Drop for Sender<E> {
+    fn drop(&mut self) {
+        if self.state.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // wake up the receiver so it sees the channel is closed
+            self.producer.wal.pushed.notify(usize::MAX);
+        }
+    }
+}
+
+/// Receives messages from a [`channel`].
+pub struct Receiver<E> {
+    consumer: WalConsumer,
+    state: Arc<State>,
+    entry: PhantomData<fn() -> E>,
+}
+
+impl<E> Receiver<E>
+where
+    E: Entry,
+{
+    /// Receives the next message, waiting for one to be sent if there is none. Returns `None`
+    /// once all senders are dropped or the receiver is closed and every message was received.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn recv(&mut self) -> Result<Option<(u64, E::Output)>> {
+        loop {
+            // listen before checking so a send in between is not missed
+            let listener = self.consumer.wal.pushed.listen();
+            // no sender is left to send more messages once this was checked
+            let disconnected = self.state.senders.load(Ordering::Acquire) == 0
+                || self.state.closed.load(Ordering::Acquire);
+            if let Some(message) = self.consumer.pop::<E>().await? {
+                return Ok(Some(message));
+            }
+            if disconnected {
+                return Ok(None);
+            }
+            listener.await;
+        }
+    }
+
+    /// Acknowledges a message as completely processed, see [`Wal::ack`].
+    ///
+    /// ## Errors
+    /// see [`Wal::ack`]
+    pub async fn ack(&mut self, id: u64) -> Result<()> {
+        self.consumer.ack(id).await
+    }
+
+    /// Reverts to the message after the last acknowledged one, see [`Wal::revert`].
+    ///
+    /// ## Errors
+    /// on IO Errors or invalid WAL files
+    pub async fn revert(&mut self) -> Result<()> {
+        self.consumer.revert().await
+    }
+
+    /// Persists the acknowledgements, see [`Wal::preserve_ack`].
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn preserve_ack(&mut self) -> Result<()> {
+        self.consumer.preserve_ack().await
+    }
+
+    /// Closes the channel so no further messages can be sent, messages that were already
+    /// sent can still be received.
+    pub fn close(&mut self) {
+        self.state.closed.store(true, Ordering::Release);
+        // wake up a pending receive so it sees the channel is closed
+        self.consumer.wal.pushed.notify(usize::MAX);
+    }
+}
+
+impl<E> Drop for Receiver<E> {
+    fn drop(&mut self) {
+        self.state.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::spawn;
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    async fn channel() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let (tx, mut rx) = super::channel::<Vec<u8>, _>(&path, Options::new(256, 100)).await?;
+        let senders: Vec<_> = (0..4)
+            .map(|s| {
+                let tx = tx.clone();
+                spawn(async move {
+                    for i in 0..10 {
+                        tx.send(vec![s, i]).await?;
+                    }
+                    Ok::<_, Error<_>>(())
+                })
+            })
+            .collect();
+        drop(tx);
+        let mut received = Vec::new();
+        while let Some((idx, message)) = rx.recv().await? {
+            assert_eq!(idx, received.len() as u64 + 1);
+            received.push(message);
+        }
+        for sender in senders {
+            sender.await?;
+        }
+        received.sort();
+        let expected: Vec<_> = (0..4)
+            .flat_map(|s| (0..10).map(move |i| vec![s, i]))
+            .collect();
+        assert_eq!(received, expected);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn restart() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        {
+            let (tx, mut rx) = super::channel(&path, Options::new(256, 100)).await?;
+            for i in 1..=3u8 {
+                tx.send(vec![i]).await?;
+            }
+            assert_eq!(rx.recv().await?, Some((1, vec![1])));
+            rx.ack(1).await?;
+            assert_eq!(rx.recv().await?, Some((2, vec![2])));
+            rx.close();
+            assert!(tx.is_closed());
+            assert!(matches!(tx.send(vec![4]).await, Err(Error::Closed)));
+            rx.preserve_ack().await?;
+        }
+        let (tx, mut rx) = super::channel::<Vec<u8>, _>(&path, Options::new(256, 100)).await?;
+        drop(tx);
+        assert_eq!(rx.recv().await?, Some((2, vec![2])));
+        assert_eq!(rx.recv().await?, Some((3, vec![3])));
+        assert_eq!(rx.recv().await?, None);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn close() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let (tx, mut rx) = super::channel(&path, Options::new(256, 100)).await?;
+        tx.send(vec![1u8]).await?;
+        rx.close();
+        // the sender is still alive, the messages sent before closing are received
+        assert_eq!(rx.recv().await?, Some((1, vec![1])));
+        assert_eq!(rx.recv().await?, None);
+        assert!(matches!(tx.send(vec![2]).await, Err(Error::Closed)));
+        Ok(())
+    }
+}
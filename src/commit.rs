@@ -0,0 +1,156 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Result;
+#[cfg(feature = "async-std")]
+use async_std::fs::{File, OpenOptions};
+use event_listener::Event;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+#[cfg(feature = "tokio")]
+use tokio::fs::{File, OpenOptions};
+
+/// Group commit of the entries in the write chunk.
+///
+/// Producers that want their entry to be durable wait on the group commit. The first of them
+/// becomes the leader and syncs the write chunk, everyone arriving while that sync is in flight
+/// waits for it to finish and is made durable together by the next sync.
+///
+/// Syncs are performed on a separate handle to the chunk so the `Wal` does not need to be
+/// locked while they are in flight.
+#[derive(Debug)]
+pub(crate) struct GroupCommit {
+    state: Mutex<State>,
+    synced: Event,
+}
+
+#[derive(Debug)]
+struct State {
+    /// The highest index written
+    written: u64,
+    /// The highest index synced
+    durable: u64,
+    /// A sync is in flight
+    syncing: bool,
+    /// The chunk unsynced entries are written to
+    chunk: PathBuf,
+    /// Handle to `chunk` used for syncing
+    file: Option<Arc<File>>,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(chunk: PathBuf, durable: u64) -> Self {
+        Self {
+            state: Mutex::new(State {
+                written: durable,
+                durable,
+                syncing: false,
+                chunk,
+                file: None,
+            }),
+            synced: Event::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // the state is always left consistent so we can ignore poisoning
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The highest index that is synced
+    pub(crate) fn durable(&self) -> u64 {
+        self.state().durable
+    }
+
+    /// Records that all entries up to `idx` are written
+    pub(crate) fn written(&self, idx: u64) {
+        let mut state = self.state();
+        state.written = state.written.max(idx);
+    }
+
+    /// Records that all entries up to `idx` got synced outside of the group commit
+    pub(crate) fn synced(&self, idx: u64) {
+        let mut state = self.state();
+        state.durable = state.durable.max(idx);
+        drop(state);
+        self.synced.notify(usize::MAX);
+    }
+
+    /// Records that new entries are written to `chunk`, all entries of the previous chunk have
+    /// to be synced before this is called.
+    pub(crate) fn rolled_over(&self, chunk: PathBuf) {
+        let mut state = self.state();
+        state.chunk = chunk;
+        state.file = None;
+    }
+
+    /// Waits until the entry `idx` is synced, either by syncing the chunk or by waiting for the
+    /// sync that is currently in flight.
+    pub(crate) async fn wait_durable(&self, idx: u64) -> Result<()> {
+        loop {
+            let listener = self.synced.listen();
+            let lead = {
+                let mut state = self.state();
+                if state.durable >= idx {
+                    return Ok(());
+                }
+                if state.syncing {
+                    None
+                } else {
+                    state.syncing = true;
+                    Some((state.written, state.chunk.clone(), state.file.clone()))
+                }
+            };
+            let Some((target, chunk, file)) = lead else {
+                listener.await;
+                continue;
+            };
+            // We are the leader now, if we get cancelled the guard hands leadership on
+            let leader = Leader(self);
+            let file = if let Some(file) = file {
+                file
+            } else {
+                let file = match OpenOptions::new().write(true).open(&chunk).await {
+                    Ok(file) => Arc::new(file),
+                    // the chunk was rolled over and possibly reclaimed since we looked at it,
+                    // rolling over synced it so the target is durable already
+                    Err(_) if self.state().chunk != chunk => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let mut state = self.state();
+                if state.chunk == chunk {
+                    state.file = Some(file.clone());
+                }
+                file
+            };
+            file.sync_data().await?;
+            let mut state = self.state();
+            state.durable = state.durable.max(target);
+            drop(state);
+            drop(leader);
+        }
+    }
+}
+
+/// Hands on leadership if the leader is done or got cancelled
+struct Leader<'commit>(&'commit GroupCommit);
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.0.state().syncing = false;
+        self.0.synced.notify(usize::MAX);
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{ack_through_expired, deserialize, file::Record, Entry, Error, Result, Wal, WalFile};
+#[cfg(feature = "async-std")]
+use async_std::{
+    fs::{self, OpenOptions},
+    io::prelude::*,
+    path::{Path, PathBuf},
+};
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "tokio")]
+use std::path::{Path, PathBuf};
+use std::{collections::VecDeque, ffi::OsStr, mem::size_of, ops::Range};
+#[cfg(feature = "tokio")]
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+/// Name of the file the cursors are persisted in
+const CURSORS: &str = "cursors";
+/// Name of the file new cursors are written to before they replace the persisted ones
+const CURSORS_TMP: &str = "cursors.tmp";
+
+//
+//  format:
+//
+//  | count: u32 | name_len: u32 | name: [u8] | ack_idx: u64 | ... |
+//
+//  Only the acknowledgement of a cursor is stored, its read position is derived from it when
+//  the cursors are loaded.
+
+/// Checks if `name` is the name of a file the cursors are persisted in
+pub(crate) fn is_cursors_file(name: Option<&OsStr>) -> bool {
+    name.is_some_and(|name| name == CURSORS || name == CURSORS_TMP)
+}
+
+/// The state of a named cursor
+#[derive(Debug, Default)]
+pub(crate) struct Cursor {
+    /// The highest index the cursor acknowledged
+    pub(crate) ack_idx: u64,
+    /// The next index to be read
+    next_idx: u64,
+    /// The chunk the cursor reads from
+    read: Option<WalFile>,
+    /// Expired entries skipped by the cursor, they are acknowledged once every entry before
+    /// them is
+    expired: VecDeque<Range<u64>>,
+}
+
+impl Cursor {
+    /// Moves the cursor to `idx`, or to the first entry if `idx` was reclaimed already
+    async fn seek(&mut self, files: &[(u64, PathBuf)], idx: u64) -> Result<()> {
+        let chunk = files
+            .iter()
+            .rposition(|(first, _)| *first <= idx)
+            .unwrap_or_default();
+        if let Some((_, path)) = files.get(chunk) {
+            let mut read = WalFile::open(path).await?;
+            read.seek_to(idx).await?;
+            self.read = Some(read);
+        }
+        self.next_idx = idx;
+        self.expired.clear();
+        Ok(())
+    }
+
+    /// Pops the next record that is not expired, moving on to the following chunk once the
+    /// current one is exhausted
+    async fn pop_record(&mut self, files: &[(u64, PathBuf)]) -> Result<Option<(u64, Record)>> {
+        loop {
+            let Some(read) = self.read.as_mut() else {
+                return Ok(None);
+            };
+            let popped = read.pop_record(&mut self.expired).await?;
+            self.next_idx = self.next_idx.max(read.next_idx_to_read);
+            // expired entries right after the acknowledged ones are acknowledged with them
+            self.ack_idx = ack_through_expired(self.ack_idx, &mut self.expired);
+            if popped.is_some() {
+                return Ok(popped);
+            }
+            let next = files
+                .iter()
+                .skip_while(|(_, path)| path.as_os_str() != read.path.as_os_str())
+                .nth(1);
+            let Some((first, path)) = next else {
+                return Ok(None);
+            };
+            let mut read = WalFile::open(path).await?;
+            read.seek_to(*first).await?;
+            self.read = Some(read);
+        }
+    }
+}
+
+/// A named cursor of a [`Wal`], created by [`Wal::cursor`].
+///
+/// Every cursor reads all entries of the WAL with its own read position and acknowledges them
+/// independent of the WAL's own read position and of the other cursors. A chunk is only
+/// reclaimed once the WAL and every cursor acknowledged all entries in it.
+///
+/// Like the WAL's own acknowledgements those of a cursor are not persisted immediately but
+/// when the WAL is closed or [`Wal::preserve_ack`] is called. The read position is not
+/// persisted: like the WAL's own read position it is derived from the acknowledgement, so
+/// when the WAL is opened a cursor continues reading at the entry following the last
+/// acknowledgement that was persisted and entries it read but did not acknowledge are read
+/// again.
+pub struct WalCursor<'wal> {
+    wal: &'wal mut Wal,
+    name: String,
+}
+
+impl Wal {
+    /// Registers a cursor named `name` reading the write-ahead-log from the oldest entry that
+    /// is still stored. Registering a cursor that exists already has no effect.
+    ///
+    /// The cursor is persisted right away.
+    ///
+    /// ## Errors
+    /// On IO Errors or invalid WAL files
+    pub async fn add_cursor(&mut self, name: &str) -> Result<()> {
+        if self.cursors.contains_key(name) {
+            return Ok(());
+        }
+        let first = self.files.first().map_or(1, |(idx, _)| (*idx).max(1));
+        let mut cursor = Cursor {
+            ack_idx: first - 1,
+            ..Cursor::default()
+        };
+        cursor.seek(&self.files, first).await?;
+        self.cursors.insert(name.to_string(), cursor);
+        self.persist_cursors().await
+    }
+
+    /// Removes the cursor named `name`, its unacknowledged entries no longer keep chunks from
+    /// being reclaimed.
+    ///
+    /// ## Errors
+    /// If no cursor with the name exists and on IO Errors
+    pub async fn remove_cursor(&mut self, name: &str) -> Result<()> {
+        if self.cursors.remove(name).is_none() {
+            return Err(Error::UnknownCursor {
+                name: name.to_string(),
+            });
+        }
+        self.persist_cursors().await?;
+        self.reclaim().await
+    }
+
+    /// The names of the registered cursors
+    pub fn cursors(&self) -> impl Iterator<Item = &str> {
+        self.cursors.keys().map(String::as_str)
+    }
+
+    /// The cursor named `name`
+    ///
+    /// ## Errors
+    /// If no cursor with the name exists
+    pub fn cursor(&mut self, name: &str) -> Result<WalCursor<'_>> {
+        if !self.cursors.contains_key(name) {
+            return Err(Error::UnknownCursor {
+                name: name.to_string(),
+            });
+        }
+        Ok(WalCursor {
+            wal: self,
+            name: name.to_string(),
+        })
+    }
+
+    /// The lowest index acknowledged by the WAL's own read position or any cursor
+    pub(crate) fn min_ack_idx(&self) -> u64 {
+        self.cursors
+            .values()
+            .map(|cursor| cursor.ack_idx)
+            .fold(self.write_file.ack_idx, u64::min)
+    }
+
+    /// Acknowledges the entries before `next` for every cursor after the chunks holding them
+    /// were dropped
+    pub(crate) async fn drop_cursors_before(&mut self, next: u64) -> Result<()> {
+        for cursor in self.cursors.values_mut() {
+            if cursor.ack_idx + 1 < next {
+                cursor.ack_idx = next - 1;
+            }
+            if cursor.next_idx <= next {
+                cursor.seek(&self.files, next).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the acknowledgements of all cursors to disk
+    pub(crate) async fn persist_cursors(&mut self) -> Result<()> {
+        let path = self.dir.join(CURSORS);
+        if self.cursors.is_empty() {
+            if fs::metadata(&path).await.is_ok() {
+                fs::remove_file(&path).await?;
+                sync_dir(&self.dir).await?;
+            }
+            return Ok(());
+        }
+        let mut buf = vec![0u8; size_of::<u32>()];
+        BigEndian::write_u32(&mut buf, self.cursors.len() as u32);
+        for (name, cursor) in &self.cursors {
+            let mut len = [0u8; size_of::<u32>()];
+            BigEndian::write_u32(&mut len, name.len() as u32);
+            buf.extend_from_slice(&len);
+            buf.extend_from_slice(name.as_bytes());
+            let mut ack_idx = [0u8; size_of::<u64>()];
+            BigEndian::write_u64(&mut ack_idx, cursor.ack_idx);
+            buf.extend_from_slice(&ack_idx);
+        }
+        // the cursors are replaced at once so a crash never leaves them partially written
+        let tmp = self.dir.join(CURSORS_TMP);
+        let mut o = OpenOptions::new();
+        o.create(true);
+        o.truncate(true);
+        o.write(true);
+        let mut file = o.open(&tmp).await?;
+        file.write_all(&buf).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, &path).await?;
+        sync_dir(&self.dir).await
+    }
+
+    /// Reads the persisted cursors and moves them to the entry following their last
+    /// acknowledgement
+    pub(crate) async fn load_cursors(&mut self) -> Result<()> {
+        let path = self.dir.join(CURSORS);
+        if fs::metadata(&path).await.is_err() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        fs::File::open(&path).await?.read_to_end(&mut buf).await?;
+        let mut data = buf.as_slice();
+        let count = take(&mut data, size_of::<u32>()).map(BigEndian::read_u32);
+        for _ in 0..count.ok_or(Error::InvalidFile)? {
+            let len = take(&mut data, size_of::<u32>())
+                .map(BigEndian::read_u32)
+                .ok_or(Error::InvalidFile)?;
+            let name = take(&mut data, len as usize)
+                .and_then(|name| String::from_utf8(name.to_vec()).ok())
+                .ok_or(Error::InvalidFile)?;
+            let ack_idx = take(&mut data, size_of::<u64>())
+                .map(BigEndian::read_u64)
+                .ok_or(Error::InvalidFile)?;
+            // chunks dropped by the overflow policy acknowledge their entries only in memory
+            let first = self.files.first().map_or(1, |(idx, _)| (*idx).max(1));
+            let mut cursor = Cursor {
+                ack_idx: ack_idx.max(first - 1),
+                ..Cursor::default()
+            };
+            cursor.seek(&self.files, cursor.ack_idx + 1).await?;
+            self.cursors.insert(name, cursor);
+        }
+        Ok(())
+    }
+}
+
+/// Syncs the directory `dir` so a file renamed or removed in it stays so after a crash
+async fn sync_dir(dir: &Path) -> Result<()> {
+    // only unix allows opening a directory to sync it
+    if cfg!(unix) {
+        fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Splits the first `len` bytes off `data`
+fn take<'data>(data: &mut &'data [u8], len: usize) -> Option<&'data [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (field, rest) = data.split_at(len);
+    *data = rest;
+    Some(field)
+}
+
+impl WalCursor<'_> {
+    fn state(&mut self) -> &mut Cursor {
+        self.wal
+            .cursors
+            .get_mut(&self.name)
+            .expect("cursors are checked when the handle is created")
+    }
+
+    /// The name of the cursor
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The highest index the cursor acknowledged
+    pub fn ack_idx(&mut self) -> u64 {
+        self.state().ack_idx
+    }
+
+    /// Pop the next entry for this cursor, returns `None` if the cursor read every entry.
+    /// Expired entries are skipped.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop<E>(&mut self) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        let Wal { cursors, files, .. } = &mut *self.wal;
+        let cursor = cursors
+            .get_mut(&self.name)
+            .expect("cursors are checked when the handle is created");
+        match cursor.pop_record(files).await? {
+            Some((idx, record)) => Ok(Some((idx, deserialize::<E>(record.data)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Acknowledges all entries up to `id` for this cursor, chunks are reclaimed once every
+    /// cursor and the WAL itself acknowledged their entries.
+    ///
+    /// ## Errors
+    ///  - if the id to ack is larger then the cursor's read id
+    ///  - if the id to ack is smaller then the cursor's acknowledged id
+    ///  - on IO Errors if reclemation of files fails
+    pub async fn ack(&mut self, id: u64) -> Result<()> {
+        let cursor = self.state();
+        if cursor.next_idx <= id || cursor.ack_idx > id {
+            return Err(Error::InvalidAckId {
+                ack_id: id,
+                read_index: cursor.next_idx,
+                write_file_ack: cursor.ack_idx,
+            });
+        }
+        cursor.ack_idx = ack_through_expired(id, &mut cursor.expired);
+        self.wal.reclaim().await
+    }
+
+    /// Moves the cursor's read position back to the entry following its last acknowledgement
+    ///
+    /// ## Errors
+    /// on IO Errors or invalid WAL files
+    pub async fn revert(&mut self) -> Result<()> {
+        let Wal { cursors, files, .. } = &mut *self.wal;
+        let cursor = cursors
+            .get_mut(&self.name)
+            .expect("cursors are checked when the handle is created");
+        cursor.seek(files, cursor.ack_idx + 1).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn cursors() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let data = |i: u8| vec![i; 100];
+        let idxs = |idxs: Range<u64>| idxs.map(|idx| (idx, data(idx as u8))).collect::<Vec<_>>();
+        async fn pop_all(cursor: &mut WalCursor<'_>) -> Result<Vec<(u64, Vec<u8>)>> {
+            let mut popped = Vec::new();
+            while let Some(entry) = cursor.pop::<Vec<u8>>().await? {
+                popped.push(entry);
+            }
+            Ok(popped)
+        }
+        let second = {
+            let mut w = Wal::open(&path, 512, 10).await?;
+            w.add_cursor("archiver").await?;
+            assert!(matches!(
+                w.cursor("forwarder"),
+                Err(Error::UnknownCursor { name }) if name == "forwarder"
+            ));
+            // spread the entries over three chunks
+            assert_eq!(w.push_batch((1..=10).map(data)).await?, 1..11);
+            assert_eq!(w.files.len(), 3);
+            let second = w.files[1].0;
+
+            // the WAL and the cursor read every entry independently
+            let mut archiver = w.cursor("archiver")?;
+            assert_eq!(archiver.pop::<Vec<u8>>().await?, Some((1, data(1))));
+            archiver.revert().await?;
+            assert_eq!(pop_all(&mut archiver).await?, idxs(1..11));
+            // entries pushed later are read from the same chunk
+            assert_eq!(w.push(data(11)).await?, 11);
+            let mut archiver = w.cursor("archiver")?;
+            assert_eq!(pop_all(&mut archiver).await?, idxs(11..12));
+            archiver.ack(second).await?;
+            assert!(matches!(
+                archiver.ack(12).await,
+                Err(Error::InvalidAckId { .. })
+            ));
+            assert_eq!(w.pop_batch::<Vec<u8>>(20, 4096).await?, idxs(1..12));
+            w.ack(3).await?;
+            assert_eq!(w.files.len(), 3);
+
+            // a lagging cursor keeps chunks from being reclaimed
+            w.add_cursor("forwarder").await?;
+            let names: Vec<_> = w.cursors().collect();
+            assert_eq!(names, vec!["archiver", "forwarder"]);
+            w.ack(11).await?;
+            assert_eq!(w.files.len(), 3);
+            assert_eq!(w.used_entries(), 11);
+            let mut forwarder = w.cursor("forwarder")?;
+            assert_eq!(pop_all(&mut forwarder).await?, idxs(1..12));
+            forwarder.ack(11).await?;
+            assert_eq!(w.files.first().map(|(idx, _)| *idx), Some(second));
+            w.close().await?;
+            second
+        };
+        // acknowledgements of the cursors are persisted
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert_eq!(w.cursor("archiver")?.ack_idx(), second);
+        assert_eq!(w.cursor("forwarder")?.ack_idx(), 11);
+        assert_eq!(
+            pop_all(&mut w.cursor("archiver")?).await?,
+            idxs(second + 1..12)
+        );
+        assert_eq!(w.cursor("forwarder")?.pop::<Vec<u8>>().await?, None);
+
+        // removing the lagging cursor reclaims the chunks it kept
+        w.remove_cursor("archiver").await?;
+        assert_eq!(w.files.len(), 1);
+        w.remove_cursor("forwarder").await?;
+        assert!(w.cursors().next().is_none());
+        assert!(fs::metadata(path.join(CURSORS)).await.is_err());
+        Ok(())
+    }
+}
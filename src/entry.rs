@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::Infallible;
+use std::{convert::Infallible, time::SystemTime};
 
 /// Represents a serializable entry in the write-ahead-log
 pub trait Entry {
@@ -45,3 +45,19 @@ impl Entry for &[u8] {
         Ok(data)
     }
 }
+
+/// Key/value headers stored with an entry, e.g. trace ids or the content type
+pub type Headers = Vec<(String, String)>;
+
+/// An entry popped from the write-ahead-log together with the metadata it was pushed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope<T> {
+    /// The index of the entry
+    pub idx: u64,
+    /// The entry
+    pub entry: T,
+    /// When the entry was pushed, `None` for entries written before timestamps were recorded
+    pub timestamp: Option<SystemTime>,
+    /// The headers the entry was pushed with
+    pub headers: Headers,
+}
@@ -14,9 +14,16 @@
 
 use crate::match_error;
 
-use super::{Entry, Error, Result};
+use super::{index::ChunkIndex, Entry, Error, Headers, Result};
 
-use std::{io::SeekFrom, mem::size_of};
+use std::{
+    collections::VecDeque,
+    io::SeekFrom,
+    mem::size_of,
+    ops::Range,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 #[cfg(test)]
 macro_rules! trace {
@@ -33,7 +40,7 @@ macro_rules! trace {
 }
 #[cfg(feature = "async-std")]
 use async_std::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::prelude::*,
     path::Path,
 };
@@ -42,7 +49,7 @@ use byteorder::{BigEndian, ByteOrder};
 use std::path::Path;
 #[cfg(feature = "tokio")]
 use tokio::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
 
@@ -53,105 +60,383 @@ enum WalData {
     Data {
         idx: u64,
         ack_idx: u64,
-        data: Vec<u8>,
+        record: Record,
     },
     /// Entry is an acknowledgement record
     Ack { idx: u64, ack_idx: u64 },
 }
 
+/// An entry as it is stored in a data record
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Record {
+    /// The serialized entry
+    pub(crate) data: Vec<u8>,
+    /// Milliseconds since the UNIX epoch after which the entry is expired
+    pub(crate) expires_at: Option<u64>,
+    /// Milliseconds since the UNIX epoch when the entry was written
+    pub(crate) timestamp: Option<u64>,
+    pub(crate) headers: Headers,
+}
+
+impl Record {
+    pub(crate) fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            expires_at: None,
+            timestamp: None,
+            headers: Headers::new(),
+        }
+    }
+
+    /// Size of the record on disk
+    pub(crate) fn size_on_disk(&self) -> u64 {
+        WalData::size_on_disk_from_len(self.lead())
+    }
+
+    /// The lead word the record is written with, its length covers the optional fields
+    fn lead(&self) -> u64 {
+        let len64 = size_of::<u64>() as u64;
+        let mut lead = WalData::FLAG_CHECKSUM | self.data.len() as u64;
+        if self.expires_at.is_some() {
+            lead = (lead | WalData::FLAG_EXPIRES) + len64;
+        }
+        if self.timestamp.is_some() {
+            lead = (lead | WalData::FLAG_TIMESTAMP) + len64;
+        }
+        if !self.headers.is_empty() {
+            let len = self.headers.iter().fold(size_of::<u32>(), |len, (k, v)| {
+                len + 2 * size_of::<u32>() + k.len() + v.len()
+            });
+            lead = (lead | WalData::FLAG_HEADERS) + len as u64;
+        }
+        lead
+    }
+
+    /// Writes the optional fields and the entry to the start of `buf`, returns the number of
+    /// bytes written
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut offset = 0;
+        for value in [self.expires_at, self.timestamp].into_iter().flatten() {
+            BigEndian::write_u64(&mut buf[offset..], value);
+            offset += size_of::<u64>();
+        }
+        if !self.headers.is_empty() {
+            BigEndian::write_u32(&mut buf[offset..], self.headers.len() as u32);
+            offset += size_of::<u32>();
+            for (key, value) in &self.headers {
+                for bytes in [key.as_bytes(), value.as_bytes()] {
+                    BigEndian::write_u32(&mut buf[offset..], bytes.len() as u32);
+                    offset += size_of::<u32>();
+                    buf[offset..offset + bytes.len()].clone_from_slice(bytes);
+                    offset += bytes.len();
+                }
+            }
+        }
+        buf[offset..offset + self.data.len()].clone_from_slice(&self.data);
+        offset + self.data.len()
+    }
+
+    /// Decodes the `data` of a record written with `lead`, returns `None` if the optional
+    /// fields flagged in `lead` are invalid
+    fn decode(lead: u64, mut data: Vec<u8>) -> Option<Self> {
+        let mut fields = Fields(&data);
+        let mut record = Record::new(Vec::new());
+        if lead & WalData::FLAG_EXPIRES != 0 {
+            record.expires_at = Some(fields.u64()?);
+        }
+        if lead & WalData::FLAG_TIMESTAMP != 0 {
+            record.timestamp = Some(fields.u64()?);
+        }
+        if lead & WalData::FLAG_HEADERS != 0 {
+            let count = fields.u32()?;
+            for _ in 0..count {
+                let key = fields.string()?;
+                let value = fields.string()?;
+                record.headers.push((key, value));
+            }
+        }
+        let start = data.len() - fields.0.len();
+        record.data = data.split_off(start);
+        Some(record)
+    }
+}
+
+/// Reads the optional fields of a record
+struct Fields<'data>(&'data [u8]);
+
+impl Fields<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(field)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(size_of::<u64>()).map(BigEndian::read_u64)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(size_of::<u32>()).map(BigEndian::read_u32)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).ok()
+    }
+}
+
 //
 //  format:
+//
+//  | lead: u64 | idx: u64 | ack_idx: u64 | data: [u8; len] | crc: u32 | trailer: u64 |
+//
+//  The lower bits of `lead` hold `len`, the upper bits hold the record flags. The trailer
+//  repeats `lead` so the last record of a file can be found by reading backwards. `crc` is
+//  a CRC32C over everything from `lead` up to the end of `data`.
+//
+//  Optional fields of data records are flagged in `lead` and stored in this order in front
+//  of the entry in `data`:
+//
+//  | expires_at: u64 | timestamp: u64 | headers | entry: [u8] |
+//
+//  with the headers stored as their count followed by the length prefixed keys and values:
+//
+//  | count: u32 | key_len: u32 | key: [u8] | value_len: u32 | value: [u8] | ... |
+//
+//  Records written before checksums were added have no `crc`, data records use `len` as
+//  lead and trailer, ack records use `u64::MAX` as lead and `0` as trailer.
 
 impl WalData {
     const OFFSET_LEN: usize = 0;
     const OFFSET_IDX: usize = Self::OFFSET_LEN + size_of::<u64>();
     const OFFSET_ACK: usize = Self::OFFSET_IDX + size_of::<u64>();
     const OFFSET_DATA: usize = Self::OFFSET_ACK + size_of::<u64>();
-    const OFFSET_TAILING_LEN: usize = Self::OFFSET_ACK + size_of::<u64>();
 
-    async fn read(f: &mut File) -> Result<Option<Self>> {
-        let mut buf = vec![0u8; size_of::<u64>() * 3];
+    /// The record carries a checksum
+    const FLAG_CHECKSUM: u64 = 1 << 63;
+    /// The record is an ack record
+    const FLAG_ACK: u64 = 1 << 62;
+    /// The data record carries an expiry time
+    const FLAG_EXPIRES: u64 = 1 << 61;
+    /// The data record carries the time it was written
+    const FLAG_TIMESTAMP: u64 = 1 << 60;
+    /// The data record carries headers
+    const FLAG_HEADERS: u64 = 1 << 59;
+    /// Mask for the data length in the lead word
+    const LEN_MASK: u64 = Self::FLAG_HEADERS - 1;
+    /// Lead word of ack records written without checksum
+    const LEGACY_ACK: u64 = u64::MAX;
+    const CHECKSUM_LEN: usize = size_of::<u32>();
+
+    /// Reads the record at the current position of `f`, `path` and `offset` are used for
+    /// error reporting. Records without a checksum are rejected if `require_checksum` is set.
+    /// Returns the record and its size on disk.
+    async fn read(
+        f: &mut File,
+        path: &std::path::Path,
+        offset: u64,
+        require_checksum: bool,
+    ) -> Result<Option<(Self, u64)>> {
+        let mut buf = vec![0u8; Self::OFFSET_DATA];
 
         // read size
         if f.read_exact(&mut buf).await.is_err() {
             return Ok(None);
         }
-        let len = BigEndian::read_u64(&buf[Self::OFFSET_LEN..]);
+        let lead = BigEndian::read_u64(&buf[Self::OFFSET_LEN..]);
 
         // read id
         let idx = BigEndian::read_u64(&buf[Self::OFFSET_IDX..]);
         // read ack_id
         let ack_idx = BigEndian::read_u64(&buf[Self::OFFSET_ACK..]);
-        if len == u64::MAX {
+        if require_checksum && lead & Self::FLAG_CHECKSUM == 0 {
+            return Err(Error::InvalidEntry);
+        }
+        if lead == Self::LEGACY_ACK {
             let mut buf = vec![0u8; size_of::<u64>()];
             // THIS is a ack token
             f.read_exact(&mut buf).await?;
             let len2 = BigEndian::read_u64(&buf);
-            if len2 != 0 {
+            return if len2 != 0 {
                 Err(Error::InvalidAck)
             } else {
-                Ok(Some(Self::Ack { ack_idx, idx }))
-            }
+                Ok(Some((
+                    Self::Ack { ack_idx, idx },
+                    Self::size_on_disk_from_len(lead),
+                )))
+            };
+        }
+        let is_ack = lead & Self::FLAG_ACK != 0;
+        let checksummed = lead & Self::FLAG_CHECKSUM != 0;
+        let len = (lead & Self::LEN_MASK) as usize;
+        let crc_len = if checksummed { Self::CHECKSUM_LEN } else { 0 };
+        let invalid = if is_ack {
+            Error::InvalidAck
         } else {
-            // read data
-            let mut data = vec![0u8; len as usize];
-            // We set the len since we know the len and then
-            unsafe { data.set_len(len as usize) };
-            f.read_exact(&mut data).await?;
+            Error::InvalidEntry
+        };
+        if is_ack && len != 0 {
+            return Err(invalid);
+        }
 
-            let mut buf = vec![0u8; size_of::<u64>()];
-            f.read_exact(&mut buf).await?;
-            let len2 = BigEndian::read_u64(&buf);
-            if len2 != len {
-                Err(Error::InvalidEntry)
-            } else {
-                Ok(Some(Self::Data { idx, ack_idx, data }))
+        // read data, checksum and trailer, the length is checked against the file first so a
+        // corrupted lead can not make us allocate more than the file holds
+        let remaining = len + crc_len + size_of::<u64>();
+        let pos = f.seek(SeekFrom::Current(0)).await?;
+        if f.metadata().await?.len().saturating_sub(pos) < remaining as u64 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut data = vec![0u8; remaining];
+        f.read_exact(&mut data).await?;
+
+        let len2 = BigEndian::read_u64(&data[len + crc_len..]);
+        let expected = if checksummed { lead } else { len as u64 };
+        if len2 != expected {
+            return Err(invalid);
+        }
+        if checksummed {
+            let crc = crc32c::crc32c_append(crc32c::crc32c(&buf), &data[..len]);
+            if crc != BigEndian::read_u32(&data[len..]) {
+                return Err(Error::ChecksumMismatch {
+                    path: path.to_path_buf(),
+                    offset,
+                });
             }
         }
+        data.truncate(len);
+        let size = Self::size_on_disk_from_len(lead);
+        if is_ack {
+            return Ok(Some((Self::Ack { idx, ack_idx }, size)));
+        }
+        let record = if checksummed {
+            Record::decode(lead, data).ok_or(invalid)?
+        } else {
+            Record::new(data)
+        };
+        Ok(Some((
+            Self::Data {
+                idx,
+                ack_idx,
+                record,
+            },
+            size,
+        )))
+    }
+
+    /// Reads the record at `offset` if it ends before `end`, returns `None` if it does not.
+    async fn read_within(
+        f: &mut File,
+        path: &std::path::Path,
+        offset: u64,
+        end: u64,
+        require_checksum: bool,
+    ) -> Result<Option<(Self, u64)>> {
+        if end.saturating_sub(offset) < size_of::<u64>() as u64 {
+            return Ok(None);
+        }
+        f.seek(SeekFrom::Start(offset)).await?;
+        let mut lead = vec![0u8; size_of::<u64>()];
+        f.read_exact(&mut lead).await?;
+        if Self::size_on_disk_from_len(BigEndian::read_u64(&lead)) > end - offset {
+            return Ok(None);
+        }
+        f.seek(SeekFrom::Start(offset)).await?;
+        Self::read(f, path, offset, require_checksum).await
     }
 
+    /// Checks if the record at `offset` that could not be read was torn while it was written.
+    ///
+    /// A torn record is incomplete: it does not fit before `end` or its trailer does not
+    /// repeat its lead, in files with checksums a lead without checksum (e.g. in a zero filled
+    /// tail) is not a record either. An incomplete record that is followed by a valid one was
+    /// not torn but corrupted.
+    async fn is_torn(
+        f: &mut File,
+        path: &std::path::Path,
+        offset: u64,
+        end: u64,
+        require_checksum: bool,
+    ) -> Result<bool> {
+        let len64 = size_of::<u64>() as u64;
+        if end.saturating_sub(offset) < len64 {
+            return Ok(true);
+        }
+        let mut buf = vec![0u8; size_of::<u64>()];
+        f.seek(SeekFrom::Start(offset)).await?;
+        f.read_exact(&mut buf).await?;
+        let lead = BigEndian::read_u64(&buf);
+        let size = Self::size_on_disk_from_len(lead);
+        if size > end - offset {
+            return Ok(true);
+        }
+        f.seek(SeekFrom::Start(offset + size - len64)).await?;
+        f.read_exact(&mut buf).await?;
+        let trailer = if lead == Self::LEGACY_ACK { 0 } else { lead };
+        let complete = BigEndian::read_u64(&buf) == trailer
+            && (!require_checksum || lead & Self::FLAG_CHECKSUM != 0);
+        if complete {
+            return Ok(false);
+        }
+        let next = Self::read_within(f, path, offset + size, end, require_checksum).await;
+        Ok(!matches!(next, Ok(Some(_))))
+    }
+
+    /// Size of a record on disk given its lead (or trailer) word
     fn size_on_disk_from_len(len: u64) -> u64 {
         let len64 = size_of::<u64>() as u64;
-        if len == u64::MAX {
+        if len == Self::LEGACY_ACK {
             len64 * 4
+        } else if len & Self::FLAG_CHECKSUM != 0 {
+            (len & Self::LEN_MASK) + (len64 * 4) + Self::CHECKSUM_LEN as u64
         } else {
             len + (len64 * 4)
         }
     }
 
-    fn size_on_disk(&self) -> u64 {
+    /// The lead word this record is written with
+    fn lead(&self) -> u64 {
         match self {
-            WalData::Data { data, .. } => WalData::size_on_disk_from_len(data.len() as u64),
-            WalData::Ack { .. } => WalData::size_on_disk_from_len(0),
+            WalData::Data { record, .. } => record.lead(),
+            WalData::Ack { .. } => Self::FLAG_CHECKSUM | Self::FLAG_ACK,
         }
     }
 
-    #[allow(clippy::uninit_vec)]
+    /// Size of this record when written to disk
+    fn size_on_disk(&self) -> u64 {
+        Self::size_on_disk_from_len(self.lead())
+    }
+
     async fn write(&self, w: &mut File) -> Result<u64> {
-        let size_on_disk = self.size_on_disk() as usize;
-        let mut buf: Vec<u8> = vec![0; size_on_disk];
-        match self {
-            WalData::Data { idx, ack_idx, data } => {
-                // id + ack_id + len + len (tailing len)
-                let len = data.len();
-                BigEndian::write_u64(&mut buf[Self::OFFSET_LEN..], len as u64);
-                BigEndian::write_u64(&mut buf[Self::OFFSET_IDX..], *idx);
-                BigEndian::write_u64(&mut buf[Self::OFFSET_ACK..], *ack_idx);
-                buf[Self::OFFSET_DATA..(Self::OFFSET_DATA + len)].clone_from_slice(data);
-                BigEndian::write_u64(&mut buf[(Self::OFFSET_DATA + len)..], len as u64); // len2
-                w.write_all(&buf).await?;
-            }
-            WalData::Ack { ack_idx, idx } => {
-                BigEndian::write_u64(&mut buf[Self::OFFSET_LEN..], u64::MAX);
-                BigEndian::write_u64(&mut buf[Self::OFFSET_IDX..], *idx);
-                BigEndian::write_u64(&mut buf[Self::OFFSET_ACK..], *ack_idx);
-                BigEndian::write_u64(&mut buf[Self::OFFSET_TAILING_LEN..], 0);
-                w.write_all(&buf).await?;
-            }
-        }
+        let mut buf = Vec::with_capacity(self.size_on_disk() as usize);
+        self.encode(&mut buf);
+        w.write_all(&buf).await?;
         Ok(self.size_on_disk())
     }
 
+    /// Appends the on disk representation of the record to `out`
+    fn encode(&self, out: &mut Vec<u8>) {
+        let offset = out.len();
+        out.resize(offset + self.size_on_disk() as usize, 0);
+        let buf = &mut out[offset..];
+        let lead = self.lead();
+        // lead + id + ack_id + data + crc + lead (trailer)
+        BigEndian::write_u64(&mut buf[Self::OFFSET_LEN..], lead);
+        BigEndian::write_u64(&mut buf[Self::OFFSET_IDX..], self.idx());
+        BigEndian::write_u64(&mut buf[Self::OFFSET_ACK..], self.ack_idx());
+        let end = if let WalData::Data { record, .. } = self {
+            Self::OFFSET_DATA + record.encode(&mut buf[Self::OFFSET_DATA..])
+        } else {
+            Self::OFFSET_DATA
+        };
+        let crc = crc32c::crc32c(&buf[..end]);
+        BigEndian::write_u32(&mut buf[end..], crc);
+        BigEndian::write_u64(&mut buf[(end + Self::CHECKSUM_LEN)..], lead);
+    }
+
     /// Fetches the index of the most recent acknowledgement
     pub fn ack_idx(&self) -> u64 {
         match self {
@@ -167,11 +452,118 @@ impl WalData {
     }
 }
 
+/// Header at the start of every chunk file
+//
+//  format:
+//
+//  | magic: [u8; 4] | version: u32 | first_idx: u64 | flags: u64 |
+//
+//  Chunk files written before the header was introduced start directly with the first
+//  record, they are recognised by not starting with the magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChunkHeader {
+    /// Format version of the chunk
+    pub(crate) version: u32,
+    /// The index of the first entry written to the chunk
+    pub(crate) first_idx: u64,
+    /// Flags the chunk was created with
+    pub(crate) flags: u64,
+}
+
+impl ChunkHeader {
+    const MAGIC: [u8; 4] = *b"QWAL";
+    /// The current format version, version 2 added optional fields to data records
+    pub(crate) const VERSION: u32 = 2;
+    /// Size of the header on disk
+    pub(crate) const SIZE: u64 = 24;
+    /// All records in the chunk carry a checksum
+    pub(crate) const FLAG_CHECKSUM: u64 = 1;
+
+    const OFFSET_VERSION: usize = Self::MAGIC.len();
+    const OFFSET_FIRST_IDX: usize = Self::OFFSET_VERSION + size_of::<u32>();
+    const OFFSET_FLAGS: usize = Self::OFFSET_FIRST_IDX + size_of::<u64>();
+
+    fn new(first_idx: u64) -> Self {
+        Self {
+            version: Self::VERSION,
+            first_idx,
+            flags: Self::FLAG_CHECKSUM,
+        }
+    }
+
+    /// Reads the header from the start of `f`, returns `None` for files without a header.
+    async fn read(f: &mut File, path: &std::path::Path) -> Result<Option<Self>> {
+        f.seek(SeekFrom::Start(0)).await?;
+        let mut buf = vec![0u8; Self::SIZE as usize];
+        if f.read_exact(&mut buf[..Self::OFFSET_VERSION])
+            .await
+            .is_err()
+            || buf[..Self::OFFSET_VERSION] != Self::MAGIC
+        {
+            return Ok(None);
+        }
+        f.read_exact(&mut buf[Self::OFFSET_VERSION..])
+            .await
+            .map_err(|_| Error::InvalidFile)?;
+        let version = BigEndian::read_u32(&buf[Self::OFFSET_VERSION..]);
+        if version == 0 || version > Self::VERSION {
+            return Err(Error::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+        Ok(Some(Self {
+            version,
+            first_idx: BigEndian::read_u64(&buf[Self::OFFSET_FIRST_IDX..]),
+            flags: BigEndian::read_u64(&buf[Self::OFFSET_FLAGS..]),
+        }))
+    }
+
+    /// Checks if `f` is shorter than a header and starts like one, which is the case if the
+    /// process died while the header was written.
+    async fn is_partial(f: &mut File) -> Result<bool> {
+        let len = f.seek(SeekFrom::End(0)).await?;
+        if len >= Self::SIZE {
+            return Ok(false);
+        }
+        let mut buf = vec![0u8; len as usize];
+        f.seek(SeekFrom::Start(0)).await?;
+        f.read_exact(&mut buf).await?;
+        let magic = buf.len().min(Self::MAGIC.len());
+        Ok(buf[..magic] == Self::MAGIC[..magic])
+    }
+
+    async fn write(&self, f: &mut File) -> Result<()> {
+        let mut buf = vec![0u8; Self::SIZE as usize];
+        buf[..Self::OFFSET_VERSION].clone_from_slice(&Self::MAGIC);
+        BigEndian::write_u32(&mut buf[Self::OFFSET_VERSION..], self.version);
+        BigEndian::write_u64(&mut buf[Self::OFFSET_FIRST_IDX..], self.first_idx);
+        BigEndian::write_u64(&mut buf[Self::OFFSET_FLAGS..], self.flags);
+        f.seek(SeekFrom::Start(0)).await?;
+        f.write_all(&buf).await?;
+        Ok(())
+    }
+}
+
+/// Describes what was discarded when a data file that did not end with a complete
+/// record, e.g. after a crash during a `push`, was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Number of bytes truncated from the end of the file
+    pub bytes: u64,
+    /// Number of (partial) records in the truncated bytes
+    pub records: u64,
+}
+
 /// Represents an data file in a write-ahead-log-structure
 #[derive(Debug)]
 pub struct WalFile {
     /// Reference to the data file
     pub(crate) file: File,
+    /// Path of the data file
+    pub(crate) path: PathBuf,
+    /// The header of the data file, `None` for files written before headers were introduced
+    pub(crate) header: Option<ChunkHeader>,
     /// The next index to be written
     pub(crate) next_idx_to_write: u64,
     /// The write offset
@@ -184,10 +576,14 @@ pub struct WalFile {
     pub(crate) ack_idx: u64,
     /// The most recent acknowledgement offset
     pub(crate) ack_written: u64,
+    /// What was discarded from the end of the file when it was opened
+    pub(crate) recovery: Recovery,
+    /// Sparse index of the offsets of the records
+    pub(crate) index: ChunkIndex,
 }
 
 impl WalFile {
-    async fn sync(&self) -> Result<()> {
+    pub(crate) async fn sync(&self) -> Result<()> {
         self.file.sync_all().await.map_err(Error::Io)
     }
 
@@ -222,38 +618,214 @@ impl WalFile {
     where
         E: Entry,
     {
-        let idx = self.next_idx_to_write;
-        self.next_idx_to_write += 1;
+        let idx = self.write(data).await?;
+        self.sync().await.map_err(match_error)?;
+        Ok(idx)
+    }
+
+    /// Writes an entry to the data file without syncing it
+    pub(crate) async fn write<E>(&mut self, data: E) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        let data = data.serialize().map_err(Error::Entry)?;
+        let idxs = self
+            .write_batch(vec![Record::new(data)])
+            .await
+            .map_err(match_error)?;
+        Ok(idxs.start)
+    }
 
+    /// Writes serialized entries to the data file with a single write without syncing them,
+    /// returns the indexes assigned to them.
+    pub(crate) async fn write_batch(&mut self, entries: Vec<Record>) -> Result<Range<u64>> {
+        let start = self.next_idx_to_write;
         let ack_idx = self.ack_idx;
+        let mut buf = Vec::with_capacity(
+            entries
+                .iter()
+                .map(|record| record.size_on_disk() as usize)
+                .sum(),
+        );
+        let mut offsets = Vec::new();
+        for record in entries {
+            let idx = self.next_idx_to_write;
+            if idx.is_multiple_of(ChunkIndex::INTERVAL) {
+                offsets.push((idx, self.write_offset + buf.len() as u64));
+            }
+            let data = WalData::Data {
+                idx,
+                ack_idx,
+                record,
+            };
+            data.encode(&mut buf);
+            self.next_idx_to_write += 1;
+        }
         self.ack_written = ack_idx;
-        let data = WalData::Data {
-            idx,
-            ack_idx,
-            data: data.serialize().map_err(Error::Entry)?,
-        };
         self.file.seek(SeekFrom::Start(self.write_offset)).await?;
-        self.write_offset += data.write(&mut self.file).await.map_err(match_error)?;
-        self.sync().await.map_err(match_error)?;
-        Ok(idx)
+        self.file.write_all(&buf).await?;
+        // make sure the write reached the OS so other handles to the file see it
+        self.file.flush().await?;
+        self.write_offset += buf.len() as u64;
+        // the index is written after the records it points to
+        self.index.append(&offsets).await?;
+        Ok(start..self.next_idx_to_write)
+    }
+
+    /// Size of a data file right after the WAL rolled over to it, holding its header and an
+    /// ack record
+    pub(crate) fn rolled_over_size() -> u64 {
+        ChunkHeader::SIZE
+            + WalData::size_on_disk_from_len(WalData::FLAG_CHECKSUM | WalData::FLAG_ACK)
     }
 
-    /// Pop an entry from the write-ahead-log data file
+    /// Pop an entry from the write-ahead-log data file, expired entries are skipped
     pub async fn pop<E>(&mut self) -> std::result::Result<Option<(u64, E::Output)>, Error<E::Error>>
     where
         E: Entry,
     {
+        match self
+            .pop_record(&mut VecDeque::new())
+            .await
+            .map_err(match_error)?
+        {
+            Some((idx, record)) => Ok(Some((
+                idx,
+                E::deserialize(record.data).map_err(Error::Entry)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Pops the next record, the indexes of skipped expired entries are added to `expired`
+    pub(crate) async fn pop_record(
+        &mut self,
+        expired: &mut VecDeque<Range<u64>>,
+    ) -> Result<Option<(u64, Record)>> {
+        self.file.seek(SeekFrom::Start(self.read_pointer)).await?;
+        let popped = self.read_next(now_millis(), expired).await?;
+        Ok(popped.map(|(idx, record, _)| (idx, record)))
+    }
+
+    /// Pops up to `max_entries` entries from the data file and appends them to `out`, returns
+    /// the number of bytes of entry data popped.
+    ///
+    /// The entries are read sequentially with a single seek. `max_bytes` is a soft limit, no
+    /// further entry is popped once it is reached. The indexes of skipped expired entries are
+    /// added to `expired`.
+    ///
+    /// If an entry can not be deserialized its error is returned. If `out` holds entries
+    /// already the entry is left to be popped next, so they can be handed out without losing
+    /// it, otherwise it is popped like it would be by [`WalFile::pop`].
+    pub(crate) async fn pop_batch<E>(
+        &mut self,
+        max_entries: usize,
+        max_bytes: u64,
+        out: &mut Vec<(u64, E::Output)>,
+        expired: &mut VecDeque<Range<u64>>,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        self.file.seek(SeekFrom::Start(self.read_pointer)).await?;
+        let now = now_millis();
+        let mut popped = 0;
+        let mut bytes = 0;
+        while popped < max_entries && bytes < max_bytes {
+            let Some((idx, record, offset)) =
+                self.read_next(now, expired).await.map_err(match_error)?
+            else {
+                break;
+            };
+            let len = record.data.len() as u64;
+            let entry = E::deserialize(record.data).map_err(Error::Entry);
+            if entry.is_err() && !out.is_empty() {
+                self.read_pointer = offset;
+                self.next_idx_to_read = idx;
+            }
+            popped += 1;
+            bytes += len;
+            out.push((idx, entry?));
+        }
+        Ok(bytes)
+    }
+
+    /// Reads up to `n` records from the read position and appends them to `out`, entries that
+    /// expired before `now` are skipped. With a `now` of 0 expired entries are read as well.
+    pub(crate) async fn read_records(
+        &mut self,
+        now: u64,
+        n: usize,
+        out: &mut Vec<(u64, Record)>,
+    ) -> Result<()> {
         self.file.seek(SeekFrom::Start(self.read_pointer)).await?;
+        for _ in 0..n {
+            match self.read_next(now, &mut VecDeque::new()).await? {
+                Some((idx, record, _)) => out.push((idx, record)),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads up to `n` records like [`WalFile::read_records`] starting at `from` or the read
+    /// position, and restores the read position afterwards. Returns the index following the
+    /// last record read.
+    pub(crate) async fn peek_records(
+        &mut self,
+        from: Option<u64>,
+        now: u64,
+        n: usize,
+        out: &mut Vec<(u64, Record)>,
+    ) -> Result<u64> {
+        let (read_pointer, next_idx_to_read) = (self.read_pointer, self.next_idx_to_read);
+        let peeked = match from {
+            Some(idx) => self.seek_to(idx).await,
+            None => Ok(()),
+        };
+        let peeked = match peeked {
+            Ok(()) => self.read_records(now, n, out).await,
+            Err(e) => Err(e),
+        };
+        let next = self.next_idx_to_read;
+        self.read_pointer = read_pointer;
+        self.next_idx_to_read = next_idx_to_read;
+        peeked.map(|()| next)
+    }
+
+    /// Reads the next data record from the current position of the file, skipping ack records
+    /// and entries that expired before `now`. Returns the record's index, the record and its
+    /// offset.
+    async fn read_next(
+        &mut self,
+        now: u64,
+        expired: &mut VecDeque<Range<u64>>,
+    ) -> Result<Option<(u64, Record, u64)>> {
         loop {
-            let data = WalData::read(&mut self.file).await.map_err(match_error)?;
-            let advance_by = data.as_ref().map(WalData::size_on_disk).unwrap_or_default();
+            let data = WalData::read(
+                &mut self.file,
+                &self.path,
+                self.read_pointer,
+                require_checksum(self.header),
+            )
+            .await?;
+            let offset = self.read_pointer;
+            let advance_by = data.as_ref().map(|(_, size)| *size).unwrap_or_default();
             trace!("Advance read pointer by: {}", advance_by);
             self.read_pointer += advance_by;
-            match data {
+            match data.map(|(data, _)| data) {
                 None => return Ok(None),
-                Some(WalData::Data { idx, data, .. }) => {
+                Some(WalData::Data { idx, record, .. }) => {
                     self.next_idx_to_read = idx + 1;
-                    return Ok(Some((idx, E::deserialize(data).map_err(Error::Entry)?)));
+                    if record.expires_at.is_some_and(|at| at <= now) {
+                        trace!("Skipping expired entry {}", idx);
+                        match expired.back_mut() {
+                            Some(range) if range.end == idx => range.end += 1,
+                            _ => expired.push_back(idx..idx + 1),
+                        }
+                        continue;
+                    }
+                    return Ok(Some((idx, record, offset)));
                 }
                 Some(WalData::Ack { .. }) => {}
             }
@@ -271,8 +843,14 @@ impl WalFile {
         o.write(false);
         o.read(true);
         let mut file = o.open(&path).await?;
-        let mut offset = 0;
-        while let Some(data) = WalData::read(&mut file).await? {
+        let path = PathBuf::from(path.as_ref().as_os_str());
+        let header = ChunkHeader::read(&mut file, &path).await?;
+        println!("{:>9}: {:?}", "header", header);
+        let mut offset = file.seek(SeekFrom::Start(data_start(header))).await?;
+        let require_checksum = require_checksum(header);
+        while let Some((data, _)) =
+            WalData::read(&mut file, &path, offset, require_checksum).await?
+        {
             println!("{offset:9}: {:?}", data);
             offset = file.seek(SeekFrom::Current(0)).await?
         }
@@ -281,6 +859,15 @@ impl WalFile {
 
     /// Open a write-ahead-log data file
     pub async fn open<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_or_create(path, 1).await
+    }
+
+    /// Open a write-ahead-log data file, if it does not exist or the process died before its
+    /// header was completely written it is created with `first_idx` as its first index.
+    pub(crate) async fn open_or_create<P>(path: P, first_idx: u64) -> Result<Self>
     where
         P: AsRef<Path>,
     {
@@ -294,53 +881,257 @@ impl WalFile {
             o.read(true);
 
             let mut file = o.open(&path).await?;
+            let path = PathBuf::from(p.as_os_str());
+            if ChunkHeader::is_partial(&mut file).await? {
+                trace!("  Header was not completely written");
+                drop(file);
+                return Self::create(p, first_idx).await;
+            }
+            let header = ChunkHeader::read(&mut file, &path).await?;
+            let len = file.seek(SeekFrom::End(0)).await?;
+            if header.is_none() {
+                // files without a header have to start with a valid record, its length is
+                // checked against the file so a foreign file can not make us allocate it
+                WalData::read_within(&mut file, &path, 0, len, false)
+                    .await
+                    .ok()
+                    .flatten()
+                    .ok_or(Error::InvalidFile)?;
+            }
 
-            file.seek(SeekFrom::End(-8)).await?;
-
-            let mut len = vec![0u8; 8];
-            file.read_exact(&mut len).await?;
-            let len = BigEndian::read_u64(&len);
-            let read_offset = file
-                .seek(SeekFrom::End(-(WalData::size_on_disk_from_len(len) as i64)))
-                .await?;
-
-            let data = WalData::read(&mut file).await?.ok_or(Error::InvalidFile)?;
-            let write_offset = file.seek(SeekFrom::Current(0)).await?;
+            let start = data_start(header);
+            let require_checksum = require_checksum(header);
+            let mut index = ChunkIndex::open(&path, len).await?;
+            let (last, recovery) =
+                match Self::read_tail(&mut file, &path, start, len, require_checksum).await? {
+                    Some(last) => (Some(last), Recovery::default()),
+                    None => {
+                        Self::recover(&mut file, &path, start, len, &index, require_checksum)
+                            .await?
+                    }
+                };
+            let write_offset = len - recovery.bytes;
+            index.truncate(write_offset).await?;
+            let (data, read_offset) = match (last, header) {
+                (Some(last), _) => last,
+                // the file holds no records yet, so it starts out as a fresh one
+                (None, Some(header)) => {
+                    let mut wal = Self::fresh(file, path, header, index);
+                    wal.recovery = recovery;
+                    trace!("Wal opened: {:?}", wal);
+                    return Ok(wal);
+                }
+                (None, None) => return Err(Error::InvalidFile),
+            };
 
             let next_idx_to_read = data.ack_idx() + 1;
             let mut wal = WalFile {
                 file,
+                path,
+                header,
                 next_idx_to_write: data.idx() + 1,
                 write_offset,
                 next_idx_to_read,
                 read_pointer: read_offset,
                 ack_idx: data.ack_idx(),
                 ack_written: data.ack_idx(),
+                recovery,
+                index,
             };
 
-            if data.idx() != wal.next_idx_to_read {
+            // only a trailing data record is guaranteed to be the next one to read, an ack
+            // record shares its index with the data record in front of it
+            if data.idx() != wal.next_idx_to_read || matches!(data, WalData::Ack { .. }) {
                 wal.seek_to(wal.next_idx_to_read).await?
             }
             trace!("Wal opened: {:?}", wal);
             Ok(wal)
         } else {
-            trace!("  Creating...");
-            let mut o = OpenOptions::new();
-            o.create(true);
-            o.read(true);
-            o.write(true);
-            Ok(WalFile {
-                file: o.open(path).await?,
-                next_idx_to_write: 1,
-                write_offset: 0,
-                next_idx_to_read: 1,
-                read_pointer: 0,
-                ack_idx: 0,
-                ack_written: 0,
-            })
+            Self::create(p, first_idx).await
         }
     }
 
+    /// Creates a new write-ahead-log data file whose first entry will be `first_idx`
+    pub(crate) async fn create<P>(path: P, first_idx: u64) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let p: &Path = path.as_ref();
+        trace!("  Creating {:?} at {}...", p.to_string_lossy(), first_idx);
+        let mut o = OpenOptions::new();
+        o.create(true);
+        o.truncate(true);
+        o.read(true);
+        o.write(true);
+        let mut file = o.open(p).await?;
+        let header = ChunkHeader::new(first_idx);
+        header.write(&mut file).await?;
+        let path = PathBuf::from(p.as_os_str());
+        let index = ChunkIndex::create(&path).await?;
+        Ok(Self::fresh(file, path, header, index))
+    }
+
+    /// A data file that holds no records yet
+    fn fresh(file: File, path: PathBuf, header: ChunkHeader, index: ChunkIndex) -> Self {
+        let first_idx = header.first_idx;
+        WalFile {
+            file,
+            path,
+            header: Some(header),
+            next_idx_to_write: first_idx,
+            write_offset: ChunkHeader::SIZE,
+            next_idx_to_read: first_idx,
+            read_pointer: ChunkHeader::SIZE,
+            ack_idx: first_idx.saturating_sub(1),
+            ack_written: first_idx.saturating_sub(1),
+            recovery: Recovery::default(),
+            index,
+        }
+    }
+
+    /// When the newest entry in the data file expires, `u64::MAX` if one of them never expires.
+    /// The entries are read from the start of the file up to the first that never expires.
+    pub(crate) async fn newest_expiry(&mut self) -> Result<u64> {
+        self.read_pointer = data_start(self.header);
+        self.file.seek(SeekFrom::Start(self.read_pointer)).await?;
+        let mut newest = 0;
+        let mut expired = VecDeque::new();
+        // with `now` at 0 no entry is skipped as expired
+        while let Some((_, record, _)) = self.read_next(0, &mut expired).await? {
+            match record.expires_at {
+                Some(at) => newest = newest.max(at),
+                None => return Ok(u64::MAX),
+            }
+        }
+        Ok(newest)
+    }
+
+    /// Deletes the data file at `path` together with its index
+    pub(crate) async fn remove<P>(path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let p: &Path = path.as_ref();
+        fs::remove_file(p).await?;
+        ChunkIndex::remove(&PathBuf::from(p.as_os_str())).await
+    }
+
+    /// The data file holds no records
+    pub(crate) fn is_fresh(&self) -> bool {
+        self.write_offset == data_start(self.header)
+    }
+
+    /// Continues the index bookkeeping of `prev`, the data file this one follows
+    pub(crate) fn continue_from(&mut self, prev: &WalFile) {
+        self.next_idx_to_read = prev.next_idx_to_read;
+        self.next_idx_to_write = prev.next_idx_to_write;
+        self.ack_idx = prev.ack_idx;
+        self.ack_written = prev.ack_written;
+    }
+
+    /// Reads the last record of a file, returns `None` if the file does not end with a
+    /// complete record.
+    async fn read_tail(
+        file: &mut File,
+        path: &std::path::Path,
+        start: u64,
+        len: u64,
+        require_checksum: bool,
+    ) -> Result<Option<(WalData, u64)>> {
+        let trailer_len = size_of::<u64>() as u64;
+        if len < start + trailer_len {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::Start(len - trailer_len)).await?;
+        let mut trailer = vec![0u8; size_of::<u64>()];
+        file.read_exact(&mut trailer).await?;
+        let size = WalData::size_on_disk_from_len(BigEndian::read_u64(&trailer));
+        if size > len - start {
+            return Ok(None);
+        }
+        let offset = len - size;
+        match WalData::read_within(file, path, offset, len, require_checksum).await {
+            Ok(Some((data, size))) if offset + size == len => Ok(Some((data, offset))),
+            Ok(_) => Ok(None),
+            Err(_) if WalData::is_torn(file, path, offset, len, require_checksum).await? => {
+                trace!("Torn tail @ {} in {:?}", offset, path);
+                Ok(None)
+            }
+            // a complete last record that can not be read is corrupted
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Scans a file whose tail is not a complete record from its last indexed record, or its
+    /// first record if none is indexed, onwards and truncates the torn record after the last
+    /// complete record. Returns the last complete record and its offset, if any, and what was
+    /// discarded.
+    ///
+    /// A complete record in front of the torn one that can not be read is not discarded, its
+    /// error is returned instead.
+    async fn recover(
+        file: &mut File,
+        path: &std::path::Path,
+        start: u64,
+        len: u64,
+        index: &ChunkIndex,
+        require_checksum: bool,
+    ) -> Result<(Option<(WalData, u64)>, Recovery)> {
+        trace!("Recovering torn WAL file {:?}", path);
+        let mut offset = start;
+        if let Some((indexed, indexed_offset)) = index.lookup(u64::MAX) {
+            if let Ok(Some((WalData::Data { idx, .. }, _))) =
+                WalData::read_within(file, path, indexed_offset, len, require_checksum).await
+            {
+                if idx == indexed {
+                    offset = indexed_offset;
+                }
+            }
+        }
+        let mut last = None;
+        loop {
+            match WalData::read_within(file, path, offset, len, require_checksum).await {
+                Ok(Some((data, size))) => {
+                    last = Some((data, offset));
+                    offset += size;
+                }
+                Ok(None) => break,
+                Err(_) if WalData::is_torn(file, path, offset, len, require_checksum).await? => {
+                    trace!("Torn record @ {} in {:?}", offset, path);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut recovery = Recovery {
+            bytes: len - offset,
+            records: 0,
+        };
+        // count the (partial) records in the discarded tail
+        let mut discarded = offset;
+        while discarded < len {
+            recovery.records += 1;
+            if len - discarded < size_of::<u64>() as u64 {
+                break;
+            }
+            file.seek(SeekFrom::Start(discarded)).await?;
+            let mut lead = vec![0u8; size_of::<u64>()];
+            file.read_exact(&mut lead).await?;
+            let size = WalData::size_on_disk_from_len(BigEndian::read_u64(&lead));
+            discarded = discarded.saturating_add(size);
+        }
+        trace!("  Truncating to {} discarding {:?}", offset, recovery);
+        file.set_len(offset).await?;
+        file.sync_all().await?;
+        Ok((last, recovery))
+    }
+
+    /// What was discarded from the end of this data file when it was opened
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
     /// Retrieve the write offset for this data file
     pub fn size(&self) -> u64 {
         self.write_offset
@@ -349,20 +1140,29 @@ impl WalFile {
     // Seek to a specified index for the next read operation
     pub async fn seek_to(&mut self, next_idx_to_read: u64) -> Result<()> {
         trace!("Seeking to {} in {:?}", next_idx_to_read, self.file);
-        self.file.seek(SeekFrom::Start(0)).await?;
-        match WalData::read(&mut self.file).await? {
+        let start = self.indexed_offset(next_idx_to_read).await;
+        self.file.seek(SeekFrom::Start(start)).await?;
+        match WalData::read(
+            &mut self.file,
+            &self.path,
+            start,
+            require_checksum(self.header),
+        )
+        .await?
+        .map(|(data, _)| data)
+        {
             // This would mean we want to seek infront of the file, in this case
             // just stick with the first element
             Some(data) if data.idx() > next_idx_to_read => {
                 trace!("First index {} > {}", data.idx(), next_idx_to_read);
-                self.read_pointer = 0;
+                self.read_pointer = start;
                 self.next_idx_to_read = data.idx();
             }
             // This is the correct element, we set the offset to zero and read from here on
             Some(data) if data.idx() == next_idx_to_read => {
                 // since the currently read data is the data we wanted to seek to
                 // we have to move the read pointer one back
-                let read_offset = 0;
+                let read_offset = start;
                 trace!(
                     "First index {} == {} => read_offset: {}",
                     data.idx(),
@@ -374,7 +1174,14 @@ impl WalFile {
             }
             Some(_) => loop {
                 let read_offset = self.pos().await?;
-                if let Some(data) = WalData::read(&mut self.file).await? {
+                if let Some((data, _)) = WalData::read(
+                    &mut self.file,
+                    &self.path,
+                    read_offset,
+                    require_checksum(self.header),
+                )
+                .await?
+                {
                     trace!(
                         "Testing {} > {} @ {}",
                         data.idx(),
@@ -398,14 +1205,39 @@ impl WalFile {
                 }
             },
             None => {
-                trace!("No entries found setting read_idx and read_offset to 0");
-                self.read_pointer = 0;
+                trace!("No entries found setting read_idx to 0 and read_offset to the start");
+                self.read_pointer = start;
                 self.next_idx_to_read = 0;
             }
         }
         Ok(())
     }
 
+    /// Offset to start scanning for `idx` from, the offset of the closest indexed entry before
+    /// it or the start of the data if the index does not hold one. An index entry that does
+    /// not point to its record is discarded.
+    async fn indexed_offset(&mut self, idx: u64) -> u64 {
+        let Some((indexed, offset)) = self.index.lookup(idx) else {
+            return data_start(self.header);
+        };
+        let end = self.write_offset;
+        let require_checksum = require_checksum(self.header);
+        match WalData::read_within(&mut self.file, &self.path, offset, end, require_checksum).await
+        {
+            Ok(Some((WalData::Data { idx, .. }, _))) if idx == indexed => offset,
+            _ => {
+                trace!(
+                    "Stale index entry {} @ {} in {:?}",
+                    indexed,
+                    offset,
+                    self.path
+                );
+                self.index.discard(indexed);
+                data_start(self.header)
+            }
+        }
+    }
+
     async fn pos(&mut self) -> Result<u64> {
         self.file
             .seek(SeekFrom::Current(0))
@@ -420,6 +1252,23 @@ impl WalFile {
     }
 }
 
+/// Milliseconds since the UNIX epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Files with a header are written with checksums on every record
+fn require_checksum(header: Option<ChunkHeader>) -> bool {
+    header.is_some_and(|h| h.flags & ChunkHeader::FLAG_CHECKSUM != 0)
+}
+
+/// Offset of the first record in a file with the given header
+fn data_start(header: Option<ChunkHeader>) -> u64 {
+    header.map_or(0, |_| ChunkHeader::SIZE)
+}
+
 #[cfg(feature = "async-std")]
 async fn exists(p: &Path) -> bool {
     p.exists().await
@@ -477,4 +1326,334 @@ mod test {
 
         Ok(())
     }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn checksum() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+
+        let second = {
+            let mut w = WalFile::open(&path).await?;
+            w.push(b"1".to_vec()).await?;
+            let second = w.size();
+            w.push(b"22".to_vec()).await?;
+            w.push(b"333".to_vec()).await?;
+            w.close().await?;
+            second
+        };
+        // flip a bit in the data of the second entry
+        let mut bytes = std::fs::read(&path)?;
+        bytes[second as usize + WalData::OFFSET_DATA] ^= 1;
+        std::fs::write(&path, bytes)?;
+
+        let mut w = WalFile::open(&path).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+        match w.pop::<Vec<u8>>().await {
+            Err(Error::ChecksumMismatch { path: p, offset }) => {
+                assert_eq!(p, path);
+                assert_eq!(offset, second);
+            }
+            other => panic!("expected checksum mismatch, got: {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn header() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+
+        {
+            let mut w = WalFile::create(&path, 42).await?;
+            assert_eq!(w.push(b"1".to_vec()).await?, 42);
+            w.close().await?;
+        }
+        let w = WalFile::open(&path).await?;
+        assert_eq!(
+            w.header,
+            Some(ChunkHeader {
+                version: ChunkHeader::VERSION,
+                first_idx: 42,
+                flags: ChunkHeader::FLAG_CHECKSUM
+            })
+        );
+        assert_eq!(w.next_idx_to_write, 43);
+        drop(w);
+
+        let mut bytes = std::fs::read(&path)?;
+        assert_eq!(&bytes[..4], b"QWAL");
+        BigEndian::write_u32(&mut bytes[4..], 99);
+        std::fs::write(&path, bytes)?;
+        match WalFile::open(&path).await {
+            Err(Error::UnsupportedVersion { path: p, version }) => {
+                assert_eq!(p, path);
+                assert_eq!(version, 99);
+            }
+            other => panic!("expected unsupported version, got: {other:?}"),
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn expiry() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+
+        let record = |data: &[u8], expires_at| Record {
+            expires_at,
+            ..Record::new(data.to_vec())
+        };
+        {
+            let mut w = WalFile::open(&path).await?;
+            let records = vec![
+                record(b"1", Some(0)),
+                record(b"22", None),
+                record(b"333", Some(1)),
+                record(b"4444", Some(u64::MAX)),
+            ];
+            let size: u64 = records.iter().map(Record::size_on_disk).sum();
+            assert_eq!(w.write_batch(records).await?, 1..5);
+            assert_eq!(w.size(), ChunkHeader::SIZE + size);
+            w.close().await?;
+        }
+        let mut w = WalFile::open(&path).await?;
+        assert_eq!(w.next_idx_to_write, 5);
+        let mut expired = VecDeque::new();
+        assert_eq!(
+            w.pop_record(&mut expired).await?,
+            Some((2, record(b"22", None)))
+        );
+        assert_eq!(
+            w.pop_record(&mut expired).await?,
+            Some((4, record(b"4444", Some(u64::MAX))))
+        );
+        assert_eq!(expired, [1..2, 3..4]);
+        drop(w);
+
+        // chunks written before records could carry optional fields are still read
+        let mut bytes = std::fs::read(&path)?;
+        BigEndian::write_u32(&mut bytes[4..], 1);
+        std::fs::write(&path, bytes)?;
+        let w = WalFile::open(&path).await?;
+        assert_eq!(w.header.map(|h| h.version), Some(1));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn sparse_index() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+        let index_path = ChunkIndex::path(&path);
+
+        let records = |idxs: Range<u8>| idxs.map(|i| Record::new(vec![i])).collect::<Vec<_>>();
+        let offsets = {
+            let mut w = WalFile::open(&path).await?;
+            w.write_batch(records(1..100)).await?;
+            w.write_batch(records(100..201)).await?;
+            let offsets = w.index.entries().to_vec();
+            w.close().await?;
+            assert_eq!(
+                offsets.iter().map(|(idx, _)| *idx).collect::<Vec<_>>(),
+                vec![64, 128, 192]
+            );
+            offsets
+        };
+        {
+            let mut w = WalFile::open(&path).await?;
+            assert_eq!(w.index.entries(), offsets.as_slice());
+            w.seek_to(150).await?;
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((150, vec![150])));
+            w.seek_to(128).await?;
+            assert_eq!(w.read_pointer, offsets[1].1);
+        }
+
+        // an index pointing to the wrong record falls back to scanning
+        let mut bytes = std::fs::read(&index_path)?;
+        BigEndian::write_u64(&mut bytes[24..], offsets[0].1);
+        std::fs::write(&index_path, bytes)?;
+        {
+            let mut w = WalFile::open(&path).await?;
+            w.seek_to(150).await?;
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((150, vec![150])));
+            assert_eq!(w.index.entries(), &offsets[..1]);
+        }
+
+        // as does a missing index
+        std::fs::remove_file(&index_path)?;
+        let mut w = WalFile::open(&path).await?;
+        assert!(w.index.entries().is_empty());
+        w.seek_to(199).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((199, vec![199])));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn torn_write() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+
+        let (intact, torn) = {
+            let mut w = WalFile::open(&path).await?;
+            w.push(b"1".to_vec()).await?;
+            w.push(b"22".to_vec()).await?;
+            let intact = w.size();
+            w.push(b"333".to_vec()).await?;
+            (intact, w.size())
+        };
+        // crash in the middle of writing the last entry
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(torn - 3)?;
+
+        {
+            let mut w = WalFile::open(&path).await?;
+            assert_eq!(
+                w.recovery(),
+                Recovery {
+                    bytes: torn - 3 - intact,
+                    records: 1
+                }
+            );
+            assert_eq!(std::fs::metadata(&path)?.len(), intact);
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, b"22".to_vec())));
+            assert_eq!(w.pop::<Vec<u8>>().await?, None);
+            assert_eq!(w.push(b"333".to_vec()).await?, 3);
+        }
+
+        // a zero filled tail must not be mistaken for entries
+        let mut bytes = std::fs::read(&path)?;
+        bytes.extend_from_slice(&[0; 64]);
+        std::fs::write(&path, bytes)?;
+        let mut w = WalFile::open(&path).await?;
+        assert_eq!(
+            w.recovery(),
+            Recovery {
+                bytes: 64,
+                records: 2
+            }
+        );
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, b"22".to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((3, b"333".to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn corrupted_record() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+
+        let (second, third, end) = {
+            let mut w = WalFile::open(&path).await?;
+            w.push(b"1".to_vec()).await?;
+            let second = w.size();
+            w.push(b"22".to_vec()).await?;
+            let third = w.size();
+            w.push(b"333".to_vec()).await?;
+            (second, third, w.size())
+        };
+        let intact = std::fs::read(&path)?;
+
+        // a corrupted record in the middle is not discarded with a torn tail
+        let mut bytes = intact.clone();
+        bytes[second as usize + WalData::OFFSET_DATA] ^= 1;
+        bytes.truncate(end as usize - 3);
+        std::fs::write(&path, &bytes)?;
+        match WalFile::open(&path).await {
+            Err(Error::ChecksumMismatch { offset, .. }) => assert_eq!(offset, second),
+            other => panic!("expected checksum mismatch, got: {other:?}"),
+        }
+        assert_eq!(std::fs::metadata(&path)?.len(), end - 3);
+
+        // neither is a corrupted last record
+        let mut bytes = intact.clone();
+        bytes[third as usize + WalData::OFFSET_DATA] ^= 1;
+        std::fs::write(&path, &bytes)?;
+        match WalFile::open(&path).await {
+            Err(Error::ChecksumMismatch { offset, .. }) => assert_eq!(offset, third),
+            other => panic!("expected checksum mismatch, got: {other:?}"),
+        }
+        assert_eq!(std::fs::metadata(&path)?.len(), end);
+
+        // or one with a corrupted trailer that is followed by a complete record
+        let mut bytes = intact.clone();
+        bytes[second as usize - 1] ^= 1;
+        bytes.truncate(end as usize - 3);
+        std::fs::write(&path, &bytes)?;
+        assert!(matches!(
+            WalFile::open(&path).await,
+            Err(Error::InvalidEntry)
+        ));
+
+        // a lead corrupted after the file was opened is checked against the file length
+        // before its record is read
+        std::fs::write(&path, &intact)?;
+        let mut w = WalFile::open(&path).await?;
+        let mut bytes = intact;
+        bytes[second as usize + 2] = 0xff;
+        std::fs::write(&path, &bytes)?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+        match w.pop::<Vec<u8>>().await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            other => panic!("expected unexpected eof, got: {other:?}"),
+        }
+        Ok(())
+    }
+
+    /// Encodes a record the way it was written before checksums were introduced
+    fn legacy_record(idx: u64, ack_idx: u64, data: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = vec![0u8; 24];
+        BigEndian::write_u64(&mut buf[8..], idx);
+        BigEndian::write_u64(&mut buf[16..], ack_idx);
+        let mut tail = [0u8; 8];
+        if let Some(data) = data {
+            BigEndian::write_u64(&mut buf, data.len() as u64);
+            BigEndian::write_u64(&mut tail, data.len() as u64);
+            buf.extend_from_slice(data);
+        } else {
+            BigEndian::write_u64(&mut buf, u64::MAX);
+        }
+        buf.extend_from_slice(&tail);
+        buf
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn legacy_format() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("wal.file");
+
+        let mut bytes = legacy_record(1, 0, Some(b"1"));
+        bytes.extend(legacy_record(1, 1, None));
+        bytes.extend(legacy_record(2, 1, Some(b"22")));
+        std::fs::write(&path, bytes)?;
+
+        {
+            let mut w = WalFile::open(&path).await?;
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, b"22".to_vec())));
+            assert_eq!(w.push(b"333".to_vec()).await?, 3);
+            w.ack(2);
+            w.close().await?;
+        }
+        let mut w = WalFile::open(&path).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((3, b"333".to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,245 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::Result;
+#[cfg(feature = "async-std")]
+use async_std::{
+    fs::{self, File, OpenOptions},
+    io::prelude::*,
+};
+use byteorder::{BigEndian, ByteOrder};
+use std::{
+    ffi::OsString,
+    io::{ErrorKind, SeekFrom},
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "tokio")]
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+#[cfg(test)]
+macro_rules! trace {
+    ($s:expr $(, $opt:expr)*) => {
+        eprintln!(concat!("[{}:{}] ", $s), file!(), line!(), $($opt),*)
+    };
+}
+
+#[cfg(not(test))]
+macro_rules! trace {
+    ($s:expr $(, $opt:expr)*) => {
+        concat!("[{}:{}] ", $s);
+    };
+}
+
+//
+//  format:
+//
+//  | idx: u64 | offset: u64 | idx: u64 | offset: u64 | ... |
+//
+//  One pair for every entry whose index is a multiple of `ChunkIndex::INTERVAL`, in the order
+//  the entries were written.
+
+/// Sparse index of a data file, mapping every [`ChunkIndex::INTERVAL`]th entry to the offset of
+/// its record.
+///
+/// The index is stored in a sidecar file next to the data file. It is only a hint: it is not
+/// synced, entries past the end of the data file are discarded when it is opened and an offset
+/// that does not hold the indexed entry is detected by the reader, which then falls back to
+/// scanning the data file. A missing index file is treated as an empty index.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkIndex {
+    file: Option<File>,
+    entries: Vec<(u64, u64)>,
+}
+
+impl ChunkIndex {
+    /// Number of entries between two indexed entries
+    pub(crate) const INTERVAL: u64 = 64;
+    /// Extension of index files
+    pub(crate) const EXTENSION: &'static str = "idx";
+    const PAIR: usize = 2 * size_of::<u64>();
+
+    /// Path of the index file belonging to the data file at `chunk`
+    pub(crate) fn path(chunk: &Path) -> PathBuf {
+        let mut path = OsString::from(chunk.as_os_str());
+        path.push(".");
+        path.push(Self::EXTENSION);
+        PathBuf::from(path)
+    }
+
+    /// Opens the index of the data file at `chunk` whose records end at `end`, creating it if
+    /// it does not exist.
+    ///
+    /// Entries that are not in ascending order or point past `end` are discarded. If the index
+    /// file can not be opened the index stays empty.
+    pub(crate) async fn open(chunk: &Path, end: u64) -> Result<Self> {
+        let path = Self::path(chunk);
+        let mut o = OpenOptions::new();
+        o.create(true);
+        o.read(true);
+        o.write(true);
+        let mut file = match o.open(&path).await {
+            Ok(file) => file,
+            Err(_) => {
+                trace!("Can not open index {:?}, falling back to scanning", path);
+                return Ok(Self::default());
+            }
+        };
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        let mut entries: Vec<(u64, u64)> = Vec::with_capacity(buf.len() / Self::PAIR);
+        for pair in buf.chunks_exact(Self::PAIR) {
+            let idx = BigEndian::read_u64(pair);
+            let offset = BigEndian::read_u64(&pair[size_of::<u64>()..]);
+            if offset >= end || entries.last().is_some_and(|(last, _)| *last >= idx) {
+                break;
+            }
+            entries.push((idx, offset));
+        }
+        let len = (entries.len() * Self::PAIR) as u64;
+        if len != buf.len() as u64 {
+            trace!(
+                "Discarding {} stale bytes of {:?}",
+                buf.len() as u64 - len,
+                path
+            );
+            file.set_len(len).await?;
+        }
+        Ok(Self {
+            file: Some(file),
+            entries,
+        })
+    }
+
+    /// Creates an empty index for the data file at `chunk`, replacing an existing one
+    pub(crate) async fn create(chunk: &Path) -> Result<Self> {
+        let mut o = OpenOptions::new();
+        o.create(true);
+        o.truncate(true);
+        o.read(true);
+        o.write(true);
+        let file = o.open(Self::path(chunk)).await?;
+        Ok(Self {
+            file: Some(file),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Deletes the index of the data file at `chunk` if there is one
+    pub(crate) async fn remove(chunk: &Path) -> Result<()> {
+        match fs::remove_file(Self::path(chunk)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The indexed entry closest before or at `idx` and its offset
+    pub(crate) fn lookup(&self, idx: u64) -> Option<(u64, u64)> {
+        let pos = self.entries.partition_point(|(i, _)| *i <= idx);
+        pos.checked_sub(1).map(|pos| self.entries[pos])
+    }
+
+    /// Discards the indexed entry `idx` and everything after it
+    pub(crate) fn discard(&mut self, idx: u64) {
+        let pos = self.entries.partition_point(|(i, _)| *i < idx);
+        self.entries.truncate(pos);
+    }
+
+    /// Discards the entries pointing at or past `end`, the end the data file was truncated to
+    pub(crate) async fn truncate(&mut self, end: u64) -> Result<()> {
+        let len = self.entries.partition_point(|(_, offset)| *offset < end);
+        if len == self.entries.len() {
+            return Ok(());
+        }
+        self.entries.truncate(len);
+        if let Some(file) = self.file.as_mut() {
+            file.set_len((len * Self::PAIR) as u64).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds the entries written at `offsets` to the index
+    pub(crate) async fn append(&mut self, offsets: &[(u64, u64)]) -> Result<()> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let mut buf = vec![0u8; offsets.len() * Self::PAIR];
+        for (pair, (idx, offset)) in buf.chunks_exact_mut(Self::PAIR).zip(offsets) {
+            BigEndian::write_u64(pair, *idx);
+            BigEndian::write_u64(&mut pair[size_of::<u64>()..], *offset);
+        }
+        file.seek(SeekFrom::End(0)).await?;
+        file.write_all(&buf).await?;
+        file.flush().await?;
+        self.entries.extend_from_slice(offsets);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn entries(&self) -> &[(u64, u64)] {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn stale_entries() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let chunk = temp_dir.path().join("00000000000000000000");
+
+        let mut index = ChunkIndex::create(&chunk).await?;
+        index.append(&[(64, 100), (128, 200)]).await?;
+        index.append(&[(192, 300)]).await?;
+        assert_eq!(index.lookup(10), None);
+        assert_eq!(index.lookup(64), Some((64, 100)));
+        assert_eq!(index.lookup(191), Some((128, 200)));
+        assert_eq!(index.lookup(1000), Some((192, 300)));
+        drop(index);
+
+        // entries past the end of the data file are discarded
+        let index = ChunkIndex::open(&chunk, 300).await?;
+        assert_eq!(index.entries(), &[(64, 100), (128, 200)]);
+        drop(index);
+        assert_eq!(fs::metadata(ChunkIndex::path(&chunk)).await?.len(), 32);
+
+        // as are torn entries
+        let mut o = OpenOptions::new();
+        o.append(true);
+        let mut file = o.open(ChunkIndex::path(&chunk)).await?;
+        file.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]).await?;
+        file.flush().await?;
+        drop(file);
+        let mut index = ChunkIndex::open(&chunk, 1000).await?;
+        assert_eq!(index.entries(), &[(64, 100), (128, 200)]);
+        index.discard(128);
+        assert_eq!(index.entries(), &[(64, 100)]);
+
+        ChunkIndex::remove(&chunk).await?;
+        ChunkIndex::remove(&chunk).await?;
+        let index = ChunkIndex::open(&chunk, 1000).await?;
+        assert!(index.entries().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    ack_through_expired, deserialize, file::now_millis, Entry, Error, Result, SharedWal, Wal,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+#[cfg(test)]
+macro_rules! trace {
+    ($s:expr $(, $opt:expr)*) => {
+        eprintln!(concat!("[{}:{}] ", $s), file!(), line!(), $($opt),*)
+    };
+}
+
+#[cfg(not(test))]
+macro_rules! trace {
+    ($s:expr $(, $opt:expr)*) => {
+        concat!("[{}:{}] ", $s);
+    };
+}
+
+/// An entry leased by [`Wal::lease`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease<T> {
+    /// The index of the entry
+    pub idx: u64,
+    /// Identifies this lease of the entry, it is required to acknowledge the entry with
+    /// [`Wal::ack_lease`]
+    pub id: u64,
+    /// The entry
+    pub entry: T,
+}
+
+/// Entries handed out by [`Wal::lease`] that are not acknowledged yet
+#[derive(Debug, Default)]
+pub(crate) struct Leases {
+    /// Leased entries with the id of their current lease and the time it ends
+    leased: BTreeMap<u64, (u64, Instant)>,
+    /// The leased entries ordered by the time their lease ends
+    deadlines: BTreeSet<(Instant, u64)>,
+    /// Leased entries that were acknowledged before every entry in front of them was
+    completed: BTreeSet<u64>,
+    /// The id of the next lease
+    next_id: u64,
+}
+
+impl Leases {
+    /// Forgets all leases, their entries are read again
+    pub(crate) fn clear(&mut self) {
+        self.leased.clear();
+        self.deadlines.clear();
+        self.completed.clear();
+    }
+
+    /// Forgets the leases of the entries before `idx`
+    pub(crate) fn discard_before(&mut self, idx: u64) {
+        self.leased = self.leased.split_off(&idx);
+        self.deadlines.retain(|(_, leased)| *leased >= idx);
+        self.completed = self.completed.split_off(&idx);
+    }
+
+    /// The entry whose lease ended first, if it ended before `now`
+    fn expired(&self, now: Instant) -> Option<u64> {
+        self.deadlines
+            .first()
+            .filter(|(until, _)| *until <= now)
+            .map(|(_, idx)| *idx)
+    }
+
+    /// Leases the entry at `idx` until `until`, superseding an earlier lease of it. Returns
+    /// the id of the lease.
+    fn lease(&mut self, idx: u64, until: Instant) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.remove(idx);
+        self.leased.insert(idx, (id, until));
+        self.deadlines.insert((until, idx));
+        id
+    }
+
+    /// Ends the lease of the entry at `idx`
+    fn remove(&mut self, idx: u64) {
+        if let Some((_, until)) = self.leased.remove(&idx) {
+            self.deadlines.remove(&(until, idx));
+        }
+    }
+}
+
+impl Wal {
+    /// Pops an entry and leases it for `duration`, returns `None` if there is no entry to lease.
+    ///
+    /// Leasing lets several workers process distinct entries in parallel: every entry is only
+    /// handed out to one of them at a time. Entries whose lease ended without them being
+    /// acknowledged with [`Wal::ack_lease`] are leased again, before any new entry. Every
+    /// lease has its own id, so a worker whose lease ended can not acknowledge the entry
+    /// for the worker it was leased to next.
+    ///
+    /// Leases are kept in memory only, after a restart or a [`Wal::revert`] all entries after
+    /// the last acknowledged one are leased again. Leasing is an alternative to `pop` and
+    /// `ack`, the two should not be mixed.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn lease<E>(&mut self, duration: Duration) -> Result<Option<Lease<E::Output>>>
+    where
+        E: Entry,
+    {
+        let now = Instant::now();
+        while let Some(idx) = self.leases.expired(now) {
+            trace!("Lease of {} ended", idx);
+            let mut records = Vec::new();
+            self.read_at(idx, 1, &mut records).await?;
+            match records.pop() {
+                Some((read, record))
+                    if read == idx && record.expires_at.is_none_or(|at| at > now_millis()) =>
+                {
+                    let id = self.leases.lease(idx, now + duration);
+                    let entry = deserialize::<E>(record.data)?;
+                    return Ok(Some(Lease { idx, id, entry }));
+                }
+                // the entry expired while it was leased
+                _ => {
+                    self.leases.remove(idx);
+                    self.complete_lease(idx).await?;
+                }
+            }
+        }
+        match self.pop_record().await? {
+            Some((idx, record)) => {
+                let id = self.leases.lease(idx, now + duration);
+                let entry = deserialize::<E>(record.data)?;
+                Ok(Some(Lease { idx, id, entry }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Acknowledges the entry at `idx` leased with the lease `id` as completely processed.
+    ///
+    /// Entries are acknowledged in order: the acknowledgement only advances, and chunks are only
+    /// reclaimed, once every entry in front of the leased one is acknowledged as well.
+    ///
+    /// ## Errors
+    ///  - with [`Error::InvalidLease`] if the entry is not leased with `id`, because the lease
+    ///    ended or the entry was leased again since
+    ///  - on IO Errors if reclemation of files fails
+    pub async fn ack_lease(&mut self, idx: u64, id: u64) -> Result<()> {
+        trace!("ACKing lease {} of {}", id, idx);
+        match self.leases.leased.get(&idx) {
+            Some((leased, until)) if *leased == id && *until > Instant::now() => {
+                self.leases.remove(idx);
+            }
+            _ => return Err(Error::InvalidLease { idx, id }),
+        }
+        self.complete_lease(idx).await
+    }
+
+    /// Marks the entry at `idx` as completed and advances the acknowledgement over the
+    /// completed entries following it
+    async fn complete_lease(&mut self, idx: u64) -> Result<()> {
+        self.leases.completed.insert(idx);
+        let mut id = ack_through_expired(self.write_file.ack_idx, &mut self.expired);
+        while self.leases.completed.remove(&(id + 1)) {
+            id = ack_through_expired(id + 1, &mut self.expired);
+        }
+        if id > self.write_file.ack_idx {
+            self.ack(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Number of entries that are leased and not acknowledged yet
+    #[must_use]
+    pub fn leased(&self) -> usize {
+        self.leases.leased.len()
+    }
+}
+
+impl SharedWal {
+    /// Pops an entry and leases it for `duration`, see [`Wal::lease`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn lease<E>(&self, duration: Duration) -> Result<Option<Lease<E::Output>>>
+    where
+        E: Entry,
+    {
+        self.wal.lock().await.lease::<E>(duration).await
+    }
+
+    /// Acknowledges a leased entry as completely processed, see [`Wal::ack_lease`].
+    ///
+    /// ## Errors
+    /// see [`Wal::ack_lease`]
+    pub async fn ack_lease(&self, idx: u64, id: u64) -> Result<()> {
+        self.wal.lock().await.ack_lease(idx, id).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::spawn;
+    use tempfile::Builder as TempDirBuilder;
+
+    /// Leases the next entry, returns its index and the id of the lease
+    async fn lease_next(w: &mut Wal, duration: Duration) -> Result<(u64, u64)> {
+        let lease = w.lease::<Vec<u8>>(duration).await?.expect("entry");
+        assert_eq!(lease.entry, vec![lease.idx as u8]);
+        Ok((lease.idx, lease.id))
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn lease() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let hour = Duration::from_secs(3600);
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert_eq!(w.lease::<Vec<u8>>(hour).await?, None);
+        for i in 1..=4 {
+            w.push(vec![i]).await?;
+        }
+        w.push_with_ttl(vec![5], Duration::ZERO).await?;
+        w.push(vec![6]).await?;
+
+        let (idx1, id1) = lease_next(&mut w, hour).await?;
+        let (idx2, id2) = lease_next(&mut w, hour).await?;
+        let (idx3, stale) = lease_next(&mut w, Duration::ZERO).await?;
+        assert_eq!((idx1, idx2, idx3), (1, 2, 3));
+        assert_eq!(w.leased(), 3);
+        // the acknowledgement only advances once the entries in front are acknowledged
+        w.ack_lease(2, id2).await?;
+        assert_eq!(w.write_file.ack_idx, 0);
+        assert!(matches!(
+            w.ack_lease(2, id2).await,
+            Err(Error::InvalidLease { idx: 2, .. })
+        ));
+        // a lease is only acknowledged with its own id
+        assert!(matches!(
+            w.ack_lease(1, id2).await,
+            Err(Error::InvalidLease { idx: 1, .. })
+        ));
+        w.ack_lease(1, id1).await?;
+        assert_eq!(w.write_file.ack_idx, 2);
+
+        // an ended lease can not be acknowledged and is handed out again before new entries
+        assert!(matches!(
+            w.ack_lease(3, stale).await,
+            Err(Error::InvalidLease { idx: 3, .. })
+        ));
+        let (idx, id3) = lease_next(&mut w, hour).await?;
+        assert_eq!(idx, 3);
+        assert_ne!(id3, stale);
+        // the worker whose lease ended can not acknowledge it for the new holder
+        assert!(matches!(
+            w.ack_lease(3, stale).await,
+            Err(Error::InvalidLease { idx: 3, .. })
+        ));
+        let (idx, id4) = lease_next(&mut w, hour).await?;
+        assert_eq!(idx, 4);
+        // the expired entry is skipped
+        let (idx, id6) = lease_next(&mut w, hour).await?;
+        assert_eq!(idx, 6);
+        assert_eq!(w.lease::<Vec<u8>>(hour).await?, None);
+        w.ack_lease(6, id6).await?;
+        w.ack_lease(4, id4).await?;
+        assert_eq!(w.write_file.ack_idx, 2);
+        w.ack_lease(3, id3).await?;
+        assert_eq!(w.write_file.ack_idx, 6);
+        assert_eq!(w.leased(), 0);
+
+        // a revert forgets the leases
+        w.push(vec![7]).await?;
+        let (idx, id) = lease_next(&mut w, hour).await?;
+        assert_eq!(idx, 7);
+        w.revert().await?;
+        assert_eq!(w.leased(), 0);
+        assert!(w.ack_lease(7, id).await.is_err());
+        assert_eq!(lease_next(&mut w, hour).await?.0, 7);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    async fn competing_consumers() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let wal = Wal::open(&path, 512, 100).await?.into_shared();
+        wal.push_batch((0..64).map(|i| vec![i])).await?;
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let wal = wal.clone();
+                spawn(async move {
+                    let mut leased = Vec::new();
+                    let lease = Duration::from_secs(3600);
+                    while let Some(Lease { idx, id, entry }) = wal.lease::<Vec<u8>>(lease).await? {
+                        assert_eq!(entry, vec![idx as u8 - 1]);
+                        wal.ack_lease(idx, id).await?;
+                        leased.push(idx);
+                    }
+                    Ok::<_, Error<_>>(leased)
+                })
+            })
+            .collect();
+        let mut leased = Vec::new();
+        for worker in workers {
+            leased.extend(worker.await?);
+        }
+        // every entry was processed exactly once
+        leased.sort_unstable();
+        assert_eq!(leased, (1..=64).collect::<Vec<_>>());
+        let wal = wal.lock().await;
+        assert_eq!(wal.write_file.ack_idx, 64);
+        assert_eq!(wal.leased(), 0);
+        Ok(())
+    }
+}
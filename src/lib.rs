@@ -12,19 +12,56 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod channel;
+mod commit;
+mod cursor;
 mod entry;
 mod file;
+mod index;
+mod lease;
+mod options;
+mod range;
+mod shared;
+mod sink;
+mod spill;
+mod split;
+mod stream;
 #[cfg(feature = "async-std")]
 use async_std::{
     fs,
     path::{Path, PathBuf},
     prelude::*,
 };
-pub use entry::Entry;
-pub use file::WalFile;
+pub use channel::{channel, Receiver, Sender};
+use commit::GroupCommit;
+use cursor::Cursor;
+pub use cursor::WalCursor;
+pub use entry::{Entry, Envelope, Headers};
+use event_listener::Event;
+use file::{now_millis, Record};
+pub use file::{Recovery, WalFile};
+use index::ChunkIndex;
+pub use lease::Lease;
+use lease::Leases;
+pub use options::{Options, OverflowPolicy, SyncPolicy};
+pub use range::WalRange;
+pub use shared::SharedWal;
+pub use sink::WalSink;
+pub use spill::SpillQueue;
+pub use split::{WalConsumer, WalProducer};
 #[cfg(feature = "tokio")]
 use std::path::{Path, PathBuf};
-use std::{convert::Infallible, ffi::OsStr, fmt::Display, io};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    ffi::OsStr,
+    fmt::Display,
+    io,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+pub use stream::WalStream;
 #[cfg(feature = "tokio")]
 use tokio::fs;
 
@@ -57,6 +94,16 @@ pub enum Error<EE: std::error::Error> {
     InvalidEntry,
     /// A WAL file is corrupted
     InvalidFile,
+    /// A WAL file was written with a format version this version of qwal can not read
+    UnsupportedVersion {
+        path: std::path::PathBuf,
+        version: u32,
+    },
+    /// The checksum of an entry in the WAL does not match its content
+    ChecksumMismatch {
+        path: std::path::PathBuf,
+        offset: u64,
+    },
     /// The WAL is exceeding it's limits and can not be written to
     SizeExceeded,
     /// Invalid ACK id is provided, it has to be between the last `ack` and the current `read`
@@ -67,6 +114,16 @@ pub enum Error<EE: std::error::Error> {
     },
     /// An invalid seek index has been given, it has to be after the last `ack` and before `write`
     InvalidIndex,
+    /// The entry at the index was reclaimed already, the oldest chunk starts at `first_idx`
+    IndexReclaimed { idx: u64, first_idx: u64 },
+    /// No entry was written at the index yet, the next entry is written at `next_idx`
+    IndexNotWritten { idx: u64, next_idx: u64 },
+    /// The channel is closed, see [`channel`]
+    Closed,
+    /// No cursor with the name is registered, see [`Wal::add_cursor`]
+    UnknownCursor { name: String },
+    /// The lease `id` of the entry at `idx` ended or was never handed out, see [`Wal::lease`]
+    InvalidLease { idx: u64, id: u64 },
     /// Incompatible entry error
     IncompatibleError,
     /// Entry Error
@@ -82,9 +139,30 @@ impl<EE: std::error::Error> Display for Error<EE> {
             Error::InvalidAck => write!(f, "Invalid WAL entry (ACK)"),
             Error::InvalidEntry => write!(f, "Invalid WAL entry (Entry)"),
             Error::InvalidFile => write!(f, "Invalid WAL File"),
+            Error::UnsupportedVersion { path, version } => write!(
+                f,
+                "Unsupported WAL file version {version} in {}",
+                path.display()
+            ),
+            Error::ChecksumMismatch { path, offset } => write!(
+                f,
+                "Checksum mismatch in WAL file {} at offset {offset}",
+                path.display()
+            ),
             Error::SizeExceeded => write!(f, "WAL Size Exceeded"),
             Error::InvalidAckId{ ack_id, read_index, write_file_ack } => write!(f, "Invalid Ack Index {ack_id}, current read index: {read_index} write_file_ack: {write_file_ack}"),
             Error::InvalidIndex => write!(f, "Invalid Index"),
+            Error::IndexReclaimed { idx, first_idx } => write!(
+                f,
+                "Index {idx} was already reclaimed, the oldest index is {first_idx}"
+            ),
+            Error::IndexNotWritten { idx, next_idx } => write!(
+                f,
+                "Index {idx} was not written yet, the next index is {next_idx}"
+            ),
+            Error::Closed => write!(f, "Channel closed"),
+            Error::UnknownCursor { name } => write!(f, "Unknown cursor {name}"),
+            Error::InvalidLease { idx, id } => write!(f, "Invalid lease {id} of index {idx}"),
             Error::IncompatibleError => write!(f, "Incompatible error"),
             Error::Entry(e) => write!(f, "Entry Error: {e}"),
         }
@@ -108,6 +186,8 @@ pub(crate) fn match_error<EE1: std::error::Error, EE2: std::error::Error>(
         Error::InvalidAck => Error::InvalidAck,
         Error::InvalidEntry => Error::InvalidEntry,
         Error::InvalidFile => Error::InvalidFile,
+        Error::UnsupportedVersion { path, version } => Error::UnsupportedVersion { path, version },
+        Error::ChecksumMismatch { path, offset } => Error::ChecksumMismatch { path, offset },
         Error::SizeExceeded => Error::SizeExceeded,
         Error::InvalidAckId {
             ack_id,
@@ -119,6 +199,11 @@ pub(crate) fn match_error<EE1: std::error::Error, EE2: std::error::Error>(
             write_file_ack,
         },
         Error::InvalidIndex => Error::InvalidIndex,
+        Error::IndexReclaimed { idx, first_idx } => Error::IndexReclaimed { idx, first_idx },
+        Error::IndexNotWritten { idx, next_idx } => Error::IndexNotWritten { idx, next_idx },
+        Error::Closed => Error::Closed,
+        Error::UnknownCursor { name } => Error::UnknownCursor { name },
+        Error::InvalidLease { idx, id } => Error::InvalidLease { idx, id },
         Error::IncompatibleError => Error::IncompatibleError,
         Error::Entry(_) => Error::IncompatibleError,
     }
@@ -174,8 +259,31 @@ pub struct Wal {
     files: Vec<(u64, PathBuf)>,
     read_file: Option<WalFile>,
     write_file: WalFile,
-    chunk_size: u64,
-    max_chunks: usize,
+    options: Options,
+    recovery: Recovery,
+    /// Entries written since the last sync
+    unsynced: u64,
+    /// Time of the last sync
+    last_sync: Instant,
+    /// Keeps track of the entries synced to disk
+    commit: Arc<GroupCommit>,
+    /// Notified when new entries can be popped
+    pushed: Arc<Event>,
+    /// Notified when acknowledgements free capacity
+    reclaimed: Arc<Event>,
+    /// Bytes in all chunks but the write chunk
+    sealed_bytes: u64,
+    /// Expired entries skipped by `pop`, they are acknowledged once every entry before them is
+    expired: VecDeque<Range<u64>>,
+    /// When the newest entry of the sealed chunks expires by their first index, `u64::MAX` if
+    /// one never expires. A chunk is read once when it is the oldest one to find out.
+    expiries: BTreeMap<u64, u64>,
+    /// Entries dropped by the overflow policy since the WAL was opened
+    dropped: u64,
+    /// Named cursors reading the WAL independent of its own read position
+    cursors: BTreeMap<String, Cursor>,
+    /// Entries leased to competing consumers
+    leases: Leases,
 }
 
 impl Wal {
@@ -191,6 +299,18 @@ impl Wal {
     /// ## Errors
     /// Errors if `path` isn't an existing directory, it has content that isn't a valid Wal.
     pub async fn open<P>(path: P, chunk_size: u64, max_chunks: usize) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_with(path, Options::new(chunk_size, max_chunks)).await
+    }
+
+    /// Open or creates a Write-Ahead Log given a path to a directory containing the Write-Ahead Log
+    /// and the [`Options`] to use.
+    ///
+    /// ## Errors
+    /// Errors if `path` isn't an existing directory, it has content that isn't a valid Wal.
+    pub async fn open_with<P>(path: P, options: Options) -> Result<Self>
     where
         P: AsRef<Path>,
     {
@@ -205,6 +325,11 @@ impl Wal {
         let mut rd = fs::read_dir(path).await?;
         while let Some(file) = next_dir_entry(&mut rd).await {
             let file = file?.path();
+            if file.extension() == Some(OsStr::new(ChunkIndex::EXTENSION))
+                || cursor::is_cursors_file(file.file_name())
+            {
+                continue;
+            }
             if fs::metadata(&file).await?.is_file() {
                 let first_idx: u64 = file
                     .file_name()
@@ -217,37 +342,89 @@ impl Wal {
         }
         files.sort();
 
-        if let Some((_, last_file)) = files.last() {
-            let write_file = WalFile::open(&last_file).await?;
+        if let Some((first_idx, last_file)) = files.last() {
+            let mut write_file = WalFile::open_or_create(&last_file, (*first_idx).max(1)).await?;
+            if write_file.is_fresh() {
+                // The process died before anything was written to the chunk, so we continue
+                // where the previous one left off.
+                if let Some((_, prev_file)) = files.iter().rev().nth(1) {
+                    let prev = WalFile::open(prev_file).await?;
+                    trace!("Continuing fresh WRITE file from: {:?}", prev);
+                    write_file.continue_from(&prev);
+                    prev.close().await?;
+                    write_file.preserve_ack().await?;
+                }
+            }
             trace!("Opening WRITE file: {:?}", write_file);
-            let next_idx_to_read = write_file.next_idx_to_read;
+            // chunks dropped by the overflow policy acknowledge their entries only in memory
+            if let Some((first_idx, _)) = files.first() {
+                if write_file.ack_idx + 1 < *first_idx {
+                    write_file.ack(first_idx - 1);
+                }
+            }
+            let mut sealed_bytes = 0;
+            for (_, file) in files.iter().rev().skip(1) {
+                sealed_bytes += fs::metadata(file).await?.len();
+            }
+            // the write file only knows about its own entries, unacknowledged entries might
+            // still be in previous chunks
+            let next_idx_to_read = write_file.ack_idx + 1;
+            let recovery = write_file.recovery();
+            let commit =
+                GroupCommit::new(write_file.path.clone(), write_file.next_idx_to_write - 1);
             let mut wal = Self {
                 dir,
                 files,
                 read_file: None,
                 write_file,
-                chunk_size,
-                max_chunks,
+                options,
+                recovery,
+                unsynced: 0,
+                last_sync: Instant::now(),
+                commit: Arc::new(commit),
+                pushed: Arc::new(Event::new()),
+                reclaimed: Arc::new(Event::new()),
+                sealed_bytes,
+                dropped: 0,
+                expired: VecDeque::new(),
+                expiries: BTreeMap::new(),
+                cursors: BTreeMap::new(),
+                leases: Leases::default(),
             };
             wal.seek_to(next_idx_to_read).await?;
+            wal.load_cursors().await?;
             Ok(wal)
         } else {
             let mut file = dir.clone();
             file.push(Self::format_file_name(0));
             let write_file = WalFile::open(&file).await?;
+            let commit =
+                GroupCommit::new(write_file.path.clone(), write_file.next_idx_to_write - 1);
             files.push((0, file));
             Ok(Self {
                 dir,
                 files,
                 read_file: None,
                 write_file,
-                chunk_size,
-                max_chunks,
+                options,
+                recovery: Recovery::default(),
+                unsynced: 0,
+                last_sync: Instant::now(),
+                commit: Arc::new(commit),
+                pushed: Arc::new(Event::new()),
+                reclaimed: Arc::new(Event::new()),
+                sealed_bytes: 0,
+                dropped: 0,
+                expired: VecDeque::new(),
+                expiries: BTreeMap::new(),
+                cursors: BTreeMap::new(),
+                leases: Leases::default(),
             })
         }
     }
 
-    /// Push a new entry into the write-ahead-log
+    /// Push a new entry into the write-ahead-log, the entry is synced to disk according to
+    /// the WAL's [`SyncPolicy`].
     ///
     /// ## Errors
     /// On IO Errors or if the entry is exceed the WAL's capacity
@@ -255,33 +432,318 @@ impl Wal {
     where
         E: Entry,
     {
-        let idx = self.write_file.push(data).await?;
-        if self.write_file.size() > self.chunk_size {
+        Ok(self.push_batch(std::iter::once(data)).await?.start)
+    }
+
+    /// Push a new entry into the write-ahead-log that expires after `ttl` instead of the
+    /// WAL's [`Options::ttl`], see [`Wal::push`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push_with_ttl<E>(
+        &mut self,
+        data: E,
+        ttl: Duration,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        let entries = serialize(std::iter::once(data), Some(ttl))?;
+        let idxs = self.push_records(entries).await.map_err(match_error)?;
+        Ok(idxs.start)
+    }
+
+    /// Push a new entry with `headers` into the write-ahead-log, see [`Wal::push`]. The
+    /// headers are returned with the entry by [`Wal::pop_envelope`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push_with_headers<E>(
+        &mut self,
+        data: E,
+        headers: Headers,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        let mut entries = serialize(std::iter::once(data), None)?;
+        entries
+            .iter_mut()
+            .for_each(|r| r.headers.clone_from(&headers));
+        let idxs = self.push_records(entries).await.map_err(match_error)?;
+        Ok(idxs.start)
+    }
+
+    /// Push a batch of entries into the write-ahead-log, returns the indexes assigned to them.
+    ///
+    /// The entries are written with a single write and synced once according to the WAL's
+    /// [`SyncPolicy`]. Chunks are cycled as they would be for single pushes, so a batch that
+    /// does not fit into the current chunk is written with one write per chunk it spans.
+    ///
+    /// Either all entries are written or, if they exceed the WAL's capacity, none of them.
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entries exceed the WAL's capacity
+    pub async fn push_batch<E, I>(
+        &mut self,
+        entries: I,
+    ) -> std::result::Result<Range<u64>, Error<E::Error>>
+    where
+        E: Entry,
+        I: IntoIterator<Item = E>,
+    {
+        let entries = serialize(entries, None)?;
+        self.push_records(entries).await.map_err(match_error)
+    }
+
+    /// Writes and syncs serialized entries
+    async fn push_records(&mut self, mut entries: VecDeque<Record>) -> Result<Range<u64>> {
+        let idxs = self.write_batch(&mut entries, false).await?;
+        self.sync_if_due().await?;
+        Ok(idxs)
+    }
+
+    /// Writes serialized entries to the write-ahead-log without syncing them, cycling chunks
+    /// once they are full. Written entries are removed from `entries`.
+    ///
+    /// If not all entries fit into the WAL's capacity `SizeExceeded` is returned, with `partial`
+    /// the entries that fit are written before, without none are.
+    pub(crate) async fn write_batch(
+        &mut self,
+        entries: &mut VecDeque<Record>,
+        partial: bool,
+    ) -> Result<Range<u64>> {
+        // expired chunks free capacity for the new entries
+        self.reclaim_expired().await?;
+        let start = self.write_file.next_idx_to_write;
+        let timestamp = now_millis();
+        let expires_at = self.options.ttl.map(expires_at);
+        for record in entries.iter_mut() {
+            record.timestamp.get_or_insert(timestamp);
+            if record.expires_at.is_none() {
+                record.expires_at = expires_at;
+            }
+        }
+        let mut fitting = self.fitting(entries);
+        while fitting < entries.len()
+            && self.options.overflow == OverflowPolicy::DropOldest
+            && self.files.len() > 1
+        {
+            self.drop_oldest().await?;
+            fitting = self.fitting(entries);
+        }
+        if fitting < entries.len() && !partial {
+            return Err(Error::SizeExceeded);
+        }
+        let mut remaining = fitting;
+        while remaining > 0 {
+            self.roll_over_if_full().await?;
+            // collect the entries that go into the current chunk, as with single pushes the
+            // chunk takes at least one entry before it is cycled
+            let mut size = self.write_file.size();
+            let mut batch = Vec::new();
+            while remaining > 0 && (batch.is_empty() || size <= self.options.chunk_size) {
+                let Some(data) = entries.pop_front() else {
+                    break;
+                };
+                size += data.size_on_disk();
+                batch.push(data);
+                remaining -= 1;
+            }
+            let idxs = self.write_file.write_batch(batch).await?;
+            self.unsynced += idxs.end - idxs.start;
+            self.commit.written(idxs.end - 1);
+            self.pushed.notify(usize::MAX);
+        }
+        // start the next chunk right away, if that is not possible the next write will fail
+        if !self.is_full() {
+            self.roll_over_if_full().await?;
+        }
+        if entries.is_empty() {
+            Ok(start..self.write_file.next_idx_to_write)
+        } else {
+            Err(Error::SizeExceeded)
+        }
+    }
+
+    /// Number of `entries` that fit into the WAL's capacity
+    fn fitting(&self, entries: &VecDeque<Record>) -> usize {
+        self.fitting_from(
+            entries,
+            self.write_file.size(),
+            self.used_bytes(),
+            self.chunk_full(),
+            self.files.len(),
+            self.used_entries(),
+        )
+    }
+
+    /// Number of `entries` that fit into the WAL's capacity once every chunk is acknowledged
+    /// and reclaimed, the entries after them can never be written as a batch
+    pub(crate) fn fitting_when_reclaimed(&self, entries: &VecDeque<Record>) -> usize {
+        let size = WalFile::rolled_over_size();
+        self.fitting_from(entries, size, size, false, 1, 0)
+    }
+
+    /// Number of `entries` that fit into the WAL's capacity starting from a write chunk of
+    /// `size` bytes, `bytes` and `count` used in total and `chunks` chunks
+    fn fitting_from(
+        &self,
+        entries: &VecDeque<Record>,
+        mut size: u64,
+        mut bytes: u64,
+        mut chunk_full: bool,
+        mut chunks: usize,
+        count: u64,
+    ) -> usize {
+        let drop_oldest = self.options.overflow == OverflowPolicy::DropOldest;
+        for (i, data) in entries.iter().enumerate() {
+            let mut needed = data.size_on_disk();
+            if chunk_full {
+                if chunks > self.options.max_chunks && !drop_oldest {
+                    return i;
+                }
+                chunks += 1;
+                size = WalFile::rolled_over_size();
+                needed += size;
+            }
+            if self
+                .options
+                .max_entries
+                .is_some_and(|max| count + i as u64 >= max)
+                || self
+                    .options
+                    .max_bytes
+                    .is_some_and(|max| bytes + needed > max)
+            {
+                return i;
+            }
+            bytes += needed;
+            size += data.size_on_disk();
+            chunk_full = size > self.options.chunk_size;
+        }
+        entries.len()
+    }
+
+    /// The current chunk exceeds the chunk size, a chunk always takes at least one entry
+    /// before it is cycled
+    fn chunk_full(&self) -> bool {
+        self.write_file.size() > self.options.chunk_size
+            && self
+                .files
+                .last()
+                .is_none_or(|(first, _)| *first < self.write_file.next_idx_to_write)
+    }
+
+    /// The current chunk is full and no new chunk can be started until chunks are reclaimed
+    fn is_full(&self) -> bool {
+        self.chunk_full()
+            && (self.files.len() > self.options.max_chunks
+                || self
+                    .options
+                    .max_bytes
+                    .is_some_and(|max| self.used_bytes() + WalFile::rolled_over_size() > max))
+    }
+
+    /// Starts a new chunk if the current one exceeds the chunk size
+    async fn roll_over_if_full(&mut self) -> Result<()> {
+        if self.chunk_full() {
             trace!(
                 "Current file exceeds max size with {} > {}",
                 self.write_file.size(),
-                self.chunk_size
+                self.options.chunk_size
             );
-            if self.files.len() > self.max_chunks {
+            if self.files.len() > self.options.max_chunks
+                && self.options.overflow != OverflowPolicy::DropOldest
+            {
                 return Err(Error::SizeExceeded);
             }
+            // the chunk is complete, so we make sure it is on disk before moving on
+            self.flush().await?;
+            self.sealed_bytes += self.write_file.size();
             let mut path = self.dir.clone();
             path.push(Self::format_file_name(self.write_file.next_idx_to_write));
             self.files
                 .push((self.write_file.next_idx_to_write, path.clone()));
-            let mut next_wal = WalFile::open(path).await.map_err(match_error)?;
-            next_wal.next_idx_to_read = self.write_file.next_idx_to_read;
-            next_wal.next_idx_to_write = self.write_file.next_idx_to_write;
-            next_wal.ack_idx = self.write_file.ack_idx;
-            next_wal.ack_written = self.write_file.ack_written;
+            let mut next_wal = WalFile::create(path, self.write_file.next_idx_to_write).await?;
+            next_wal.continue_from(&self.write_file);
             std::mem::swap(&mut next_wal, &mut self.write_file);
 
-            self.write_file.preserve_ack().await.map_err(match_error)?;
+            self.write_file.preserve_ack().await?;
+            self.commit.rolled_over(self.write_file.path.clone());
+            self.synced();
             if self.read_file.is_none() {
                 self.read_file = Some(next_wal)
             }
+            while self.files.len() > self.options.max_chunks + 1 {
+                self.drop_oldest().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest chunk and acknowledges all entries in it, the entries that were not
+    /// acknowledged before are lost.
+    async fn drop_oldest(&mut self) -> Result<()> {
+        self.dropped += self.remove_oldest().await?;
+        Ok(())
+    }
+
+    /// Deletes the oldest chunks once every entry in them expired, they are acknowledged like
+    /// popped expired entries are. Cursors and leases move past them as well.
+    async fn reclaim_expired(&mut self) -> Result<()> {
+        let now = now_millis();
+        let mut reclaimed = false;
+        // the write chunk is never reclaimed
+        while let [(first, path), _, ..] = self.files.as_slice() {
+            let first = *first;
+            let newest = match self.expiries.get(&first) {
+                Some(newest) => *newest,
+                None => {
+                    let newest = WalFile::open(path).await?.newest_expiry().await?;
+                    self.expiries.insert(first, newest);
+                    newest
+                }
+            };
+            if newest > now {
+                break;
+            }
+            trace!("All entries in Wal File@{} expired", first);
+            self.remove_oldest().await?;
+            reclaimed = true;
+        }
+        if reclaimed {
+            self.reclaimed.notify(usize::MAX);
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest chunk and acknowledges all entries in it, returns how many of them
+    /// were not acknowledged before
+    async fn remove_oldest(&mut self) -> Result<u64> {
+        let (first, path) = self.files.remove(0);
+        self.expiries.remove(&first);
+        // the write chunk is never removed so there always is a next chunk
+        let next = self.files.first().map_or(first, |(idx, _)| *idx);
+        let lost = next.saturating_sub(first.max(self.write_file.ack_idx + 1));
+        trace!(
+            "Removing Wal File@{} {:?} with {} unacknowledged entries",
+            first,
+            path,
+            lost
+        );
+        let len = fs::metadata(&path).await?.len();
+        self.sealed_bytes = self.sealed_bytes.saturating_sub(len);
+        WalFile::remove(&path).await?;
+        if self.write_file.ack_idx + 1 < next {
+            self.write_file.ack(next - 1);
+        }
+        if self.read_idx() <= next {
+            self.seek_to(next).await?;
         }
-        Ok(idx)
+        self.leases.discard_before(next);
+        self.drop_cursors_before(next).await?;
+        Ok(lost)
     }
 
     /// Pop an existing entry from the write-ahead-log, returs `None` if no new entry exists
@@ -292,37 +754,256 @@ impl Wal {
     where
         E: Entry,
     {
-        'outer: loop {
-            if let Some(read) = self.read_file.as_mut() {
-                trace!("Read file exists: {:?}", read);
-                if let Some(r) = read.pop::<E>().await.map_err(match_error)? {
-                    trace!("  We found an entry: {}", r.0);
-                    return Ok(Some(r));
-                }
-                trace!("  We are exhausted.");
-                if let Some((_, files)) = self.files.split_last() {
-                    for (idx, path) in files {
-                        trace!(
-                            "  testing next file with {} >= {}",
-                            *idx,
-                            read.next_idx_to_read
-                        );
-                        if *idx >= read.next_idx_to_read {
-                            *read = WalFile::open(path).await?;
-                            continue 'outer;
-                        }
+        match self.pop_record().await? {
+            Some((idx, record)) => Ok(Some((idx, deserialize::<E>(record.data)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Pop an existing entry from the write-ahead-log together with the time it was pushed
+    /// and its headers, returs `None` if no new entry exists
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_envelope<E>(&mut self) -> Result<Option<Envelope<E::Output>>>
+    where
+        E: Entry,
+    {
+        match self.pop_record().await? {
+            Some((idx, record)) => Ok(Some(envelope::<E>(idx, record)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Pops the next record that is not expired
+    ///
+    /// Nothing is awaited once the read position moved past the popped entry, so cancelling
+    /// the pop does not lose it. Expired entries skipped on the way to it are acknowledged by
+    /// the next `pop` or `ack`.
+    async fn pop_record(&mut self) -> Result<Option<(u64, Record)>> {
+        loop {
+            self.ack_expired().await?;
+            let read = match self.read_file.as_mut() {
+                Some(read) => read,
+                None => &mut self.write_file,
+            };
+            trace!("Reading from: {:?}", read);
+            if let Some(r) = read.pop_record(&mut self.expired).await? {
+                trace!("  We found an entry: {}", r.0);
+                return Ok(Some(r));
+            }
+            self.ack_expired().await?;
+            if !self.next_read_chunk().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Returns the entry the next `pop` would return without popping it, the read position
+    /// is left untouched.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn peek<E>(&mut self) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        Ok(self.peek_n::<E>(1).await?.into_iter().next())
+    }
+
+    /// Returns up to `n` entries the next `pop`s would return without popping them, the read
+    /// position is left untouched.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn peek_n<E>(&mut self, n: usize) -> Result<Vec<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        let now = now_millis();
+        let mut records = Vec::new();
+        if let Some(read) = self.read_file.as_mut() {
+            let mut next = read.peek_records(None, now, n, &mut records).await?;
+            // continue with the chunks following the read chunk, see `next_read_chunk`
+            if let Some((_, files)) = self.files.split_last() {
+                let chunks = files
+                    .iter()
+                    .skip_while(|(_, path)| path.as_os_str() != read.path.as_os_str());
+                for (_, path) in chunks.skip(1) {
+                    if records.len() >= n {
+                        break;
                     }
+                    let mut chunk = WalFile::open(path).await?;
+                    chunk
+                        .read_records(now, n - records.len(), &mut records)
+                        .await?;
+                    next = chunk.next_idx_to_read;
+                }
+            }
+            if records.len() < n {
+                self.write_file
+                    .peek_records(Some(next), now, n - records.len(), &mut records)
+                    .await?;
+            }
+        } else {
+            self.write_file
+                .peek_records(None, now, n, &mut records)
+                .await?;
+        }
+        records
+            .into_iter()
+            .map(|(idx, record)| Ok((idx, deserialize::<E>(record.data)?)))
+            .collect()
+    }
+
+    /// Reads the entry at `idx` as long as its chunk is not reclaimed, independent of whether
+    /// it was popped, acknowledged or expired already. The read position is left untouched.
+    ///
+    /// ## Errors
+    /// Errors with [`Error::IndexReclaimed`] if the entry's chunk was reclaimed already, with
+    /// [`Error::IndexNotWritten`] if no entry was written at `idx` yet and on IO Errors or
+    /// invalid WAL files
+    pub async fn get<E>(&mut self, idx: u64) -> Result<E::Output>
+    where
+        E: Entry,
+    {
+        let mut records = Vec::new();
+        self.read_at(idx, 1, &mut records).await?;
+        match records.pop() {
+            Some((read, record)) if read == idx => deserialize::<E>(record.data),
+            // every index before the next one was written, so an entry that is missing from
+            // its chunk is gone as well
+            _ => Err(Error::IndexReclaimed {
+                idx,
+                first_idx: self.first_idx(),
+            }),
+        }
+    }
+
+    /// The index of the first entry in the oldest chunk
+    pub(crate) fn first_idx(&self) -> u64 {
+        self.files
+            .first()
+            .map_or(self.write_file.next_idx_to_write, |(first, _)| {
+                // the first chunk of a WAL is named 0 but starts at 1
+                (*first).max(1)
+            })
+    }
+
+    /// Reads up to `n` records starting at `idx` from the chunk holding `idx` without moving
+    /// the read position, expired entries are read as well. Returns the index following the
+    /// last record read.
+    pub(crate) async fn read_at(
+        &mut self,
+        idx: u64,
+        n: usize,
+        out: &mut Vec<(u64, Record)>,
+    ) -> Result<u64> {
+        let next_idx = self.write_file.next_idx_to_write;
+        // indexes start at 1
+        if idx == 0 || idx >= next_idx {
+            return Err(Error::IndexNotWritten { idx, next_idx });
+        }
+        let Some(chunk) = self.files.iter().rposition(|(first, _)| *first <= idx) else {
+            return Err(Error::IndexReclaimed {
+                idx,
+                first_idx: self.first_idx(),
+            });
+        };
+        let path = &self.files[chunk].1;
+        if chunk + 1 == self.files.len() {
+            self.write_file.peek_records(Some(idx), 0, n, out).await
+        } else if let Some(read) = self
+            .read_file
+            .as_mut()
+            .filter(|read| read.path.as_os_str() == path.as_os_str())
+        {
+            read.peek_records(Some(idx), 0, n, out).await
+        } else {
+            let mut file = WalFile::open(path).await?;
+            file.seek_to(idx).await?;
+            file.read_records(0, n, out).await?;
+            Ok(file.next_idx_to_read)
+        }
+    }
+
+    /// Pop up to `max_entries` existing entries from the write-ahead-log, returns an empty
+    /// vector if no new entry exists.
+    ///
+    /// The entries are read sequentially through the chunks. `max_bytes` limits the size of
+    /// the entries' serialized data. Like the other limits it is a soft limit: entries are
+    /// popped until it is reached, so the last entry can exceed it.
+    ///
+    /// If an entry can not be deserialized the entries popped before it are returned, the
+    /// next call pops the entry and returns its error.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors, invalid WAL files or if the first entry can not be deserialized
+    pub async fn pop_batch<E>(
+        &mut self,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Vec<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        while entries.len() < max_entries && bytes < max_bytes {
+            let read = match self.read_file.as_mut() {
+                Some(read) => read,
+                None => &mut self.write_file,
+            };
+            let popped = read
+                .pop_batch::<E>(
+                    max_entries - entries.len(),
+                    max_bytes - bytes,
+                    &mut entries,
+                    &mut self.expired,
+                )
+                .await;
+            self.ack_expired().await?;
+            match popped {
+                Ok(popped) => bytes += popped,
+                // the entry that can not be deserialized is popped by the next call
+                Err(Error::Entry(_)) if !entries.is_empty() => break,
+                Err(e) => return Err(match_error(e)),
+            }
+            // if the limits aren't reached the chunk is exhausted
+            if entries.len() < max_entries && bytes < max_bytes && !self.next_read_chunk().await? {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Moves reading on to the next chunk once the current read chunk is exhausted, returns
+    /// `false` if there is no chunk left to read.
+    async fn next_read_chunk(&mut self) -> Result<bool> {
+        let Some(read) = self.read_file.as_mut() else {
+            return Ok(false);
+        };
+        trace!("  We are exhausted.");
+        if let Some((_, files)) = self.files.split_last() {
+            for (idx, path) in files {
+                trace!(
+                    "  testing next file with {} >= {}",
+                    *idx,
+                    read.next_idx_to_read
+                );
+                if *idx >= read.next_idx_to_read {
+                    *read = WalFile::open(path).await?;
+                    return Ok(true);
                 }
             }
-            break;
         }
         trace!("read_file => None");
         if let Some(rf) = self.read_file.take() {
             trace!("read_file.next_idx: {}", rf.next_idx_to_read);
-            self.write_file.next_idx_to_read = rf.next_idx_to_read;
+            // the write file might have been read past this already before a revert
+            self.write_file.seek_to(rf.next_idx_to_read).await?;
         }
-
-        self.write_file.pop::<E>().await.map_err(match_error)
+        Ok(true)
     }
 
     /// Acknowledges an entry as completely processed allowing it to be reclaimed.
@@ -355,8 +1036,16 @@ impl Wal {
             });
         }
 
+        let id = ack_through_expired(id, &mut self.expired);
         self.write_file.ack(id);
+        self.reclaim().await
+    }
 
+    /// Deletes the chunks whose entries are acknowledged by the WAL's own read position and by
+    /// every cursor or expired
+    async fn reclaim(&mut self) -> Result<()> {
+        self.reclaim_expired().await?;
+        let id = self.min_ack_idx();
         let mut files = self.files.iter();
         let mut to_delete = None;
         let mut cnt = 0;
@@ -384,23 +1073,47 @@ impl Wal {
             for (id, f) in files {
                 if id <= to_delete {
                     trace!("  Deleting Wal File@{} {:?}", id, f.to_string_lossy());
-                    fs::remove_file(f).await?;
+                    let len = fs::metadata(&f).await?.len();
+                    self.sealed_bytes = self.sealed_bytes.saturating_sub(len);
+                    WalFile::remove(&f).await?;
+                    self.expiries.remove(&id);
                 } else {
                     self.files.push((id, f))
                 }
             }
         }
+        self.reclaimed.notify(usize::MAX);
 
         Ok(())
     }
 
+    /// Acknowledges the expired entries that directly follow the acknowledged ones, so their
+    /// chunks can be reclaimed
+    async fn ack_expired(&mut self) -> Result<()> {
+        let ack_idx = self.write_file.ack_idx;
+        if self
+            .expired
+            .front()
+            .is_some_and(|expired| expired.start <= ack_idx + 1)
+        {
+            self.ack(ack_idx).await?;
+        }
+        Ok(())
+    }
+
     /// Reverts the read index back to the next item after the last acknowledged index.
     ///
     /// ## Errors
     /// on IO Errors or invalid WAL files
     pub async fn revert(&mut self) -> Result<()> {
         trace!("Reverting to {}", self.write_file.ack_idx + 1);
-        self.seek_to(self.write_file.ack_idx + 1).await
+        // expired entries are found again when they are read again
+        self.expired.clear();
+        // as are leased entries
+        self.leases.clear();
+        self.seek_to(self.write_file.ack_idx + 1).await?;
+        self.pushed.notify(usize::MAX);
+        Ok(())
     }
 
     /// Cleanly closes a WAL file and persists it's ack index if needed.
@@ -413,15 +1126,88 @@ impl Wal {
     }
 
     /// Persists an ack, this is usually not needed as `push` will do the same. Use this with
-    /// caution as it will have a performanc impact.
+    /// caution as it will have a performanc impact. The acknowledgements of the cursors are
+    /// persisted as well.
+    ///
+    /// The ack is only appended to the write chunk, sealed chunks are never written to again.
     pub async fn preserve_ack(&mut self) -> Result<()> {
         self.write_file.preserve_ack().await?;
-        if let Some(f) = self.read_file.take() {
-            f.close().await?;
+        self.persist_cursors().await?;
+        self.synced();
+        Ok(())
+    }
+
+    /// Number of entries dropped by [`OverflowPolicy::DropOldest`] before they were
+    /// acknowledged since the WAL was opened.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Bytes the WAL's chunks take up on disk
+    pub fn used_bytes(&self) -> u64 {
+        self.sealed_bytes + self.write_file.size()
+    }
+
+    /// Number of entries that were pushed but not acknowledged yet by the WAL's own read
+    /// position and every cursor
+    pub fn used_entries(&self) -> u64 {
+        (self.write_file.next_idx_to_write - 1).saturating_sub(self.min_ack_idx())
+    }
+
+    /// What was discarded from the end of the WAL when it was opened. A non empty recovery
+    /// means the WAL was not closed cleanly and the entry being pushed at that time was lost.
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    /// Syncs all entries written so far to disk, regardless of the [`SyncPolicy`].
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.unsynced > 0 && self.durable_idx() < self.write_file.next_idx_to_write - 1 {
+            trace!("Syncing {} entries", self.unsynced);
+            self.write_file.sync().await?;
+            self.synced();
+        }
+        Ok(())
+    }
+
+    /// The highest index that is synced to disk, all entries up to and including it survive
+    /// a crash.
+    pub fn durable_idx(&self) -> u64 {
+        self.commit.durable()
+    }
+
+    /// Waits until the entry with index `idx` is durable, syncing the WAL if it is not yet.
+    ///
+    /// ## Errors
+    /// On IO Errors or if `idx` was not written yet
+    pub async fn wait_durable(&mut self, idx: u64) -> Result<()> {
+        if idx >= self.write_file.next_idx_to_write {
+            return Err(Error::InvalidIndex);
+        }
+        if idx > self.durable_idx() {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Syncs if the [`SyncPolicy`] requires it
+    pub(crate) async fn sync_if_due(&mut self) -> Result<()> {
+        if self.options.sync.is_due(self.unsynced, self.last_sync) {
+            self.flush().await?;
         }
         Ok(())
     }
 
+    /// Records that everything written so far is synced
+    fn synced(&mut self) {
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        self.commit.synced(self.write_file.next_idx_to_write - 1);
+    }
+
     /// Name formating for WAL files
     fn format_file_name(idx: u64) -> String {
         format!("{:020}", idx)
@@ -469,6 +1255,71 @@ impl Wal {
     }
 }
 
+/// Extends an acknowledgement of `id` over the expired entries right after it, returns the
+/// index acknowledged
+pub(crate) fn ack_through_expired(mut id: u64, expired: &mut VecDeque<Range<u64>>) -> u64 {
+    while let Some(range) = expired.front() {
+        if range.start > id + 1 {
+            break;
+        }
+        id = id.max(range.end - 1);
+        expired.pop_front();
+    }
+    id
+}
+
+/// Serializes entries for writing them, entries without a `ttl` expire according to the
+/// WAL's options
+pub(crate) fn serialize<E, I>(
+    entries: I,
+    ttl: Option<Duration>,
+) -> std::result::Result<VecDeque<Record>, Error<E::Error>>
+where
+    E: Entry,
+    I: IntoIterator<Item = E>,
+{
+    let expires_at = ttl.map(expires_at);
+    entries
+        .into_iter()
+        .map(|entry| {
+            let data = entry.serialize().map_err(Error::Entry)?;
+            let mut record = Record::new(data);
+            record.expires_at = expires_at;
+            Ok(record)
+        })
+        .collect()
+}
+
+/// Deserializes a popped entry
+pub(crate) fn deserialize<E>(data: Vec<u8>) -> Result<E::Output>
+where
+    E: Entry,
+{
+    E::deserialize(data)
+        .map_err(Error::<E::Error>::Entry)
+        .map_err(match_error)
+}
+
+/// Deserializes a popped record into an envelope
+pub(crate) fn envelope<E>(idx: u64, record: Record) -> Result<Envelope<E::Output>>
+where
+    E: Entry,
+{
+    Ok(Envelope {
+        idx,
+        entry: deserialize::<E>(record.data)?,
+        timestamp: record
+            .timestamp
+            .map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+        headers: record.headers,
+    })
+}
+
+/// The expiry time of an entry written now with the given `ttl`
+fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
 #[cfg(feature = "async-std")]
 async fn next_dir_entry(rd: &mut fs::ReadDir) -> Option<io::Result<fs::DirEntry>> {
     rd.next().await
@@ -482,8 +1333,31 @@ async fn next_dir_entry(rd: &mut fs::ReadDir) -> Option<io::Result<fs::DirEntry>
 mod test {
 
     use super::*;
+    use crate::file::ChunkHeader;
+    use std::{future::Future, time::SystemTime};
     use tempfile::Builder as TempDirBuilder;
 
+    /// Spawns a task, the returned future resolves to its output
+    #[cfg(feature = "tokio")]
+    pub(crate) fn spawn<F>(f: F) -> impl Future<Output = F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let handle = tokio::spawn(f);
+        async move { handle.await.expect("task panicked") }
+    }
+
+    /// Spawns a task, the returned future resolves to its output
+    #[cfg(feature = "async-std")]
+    pub(crate) fn spawn<F>(f: F) -> impl Future<Output = F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        async_std::task::spawn(f)
+    }
+
     #[cfg_attr(feature = "async-std", async_std::test)]
     #[cfg_attr(feature = "tokio", tokio::test)]
     async fn wal() -> Result<()> {
@@ -556,4 +1430,604 @@ mod test {
         w.ack(3).await?;
         Ok(())
     }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn sync_policy() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut options = Options::new(1024, 10);
+        options.sync = SyncPolicy::Never;
+        let mut w = Wal::open_with(&path, options.clone()).await?;
+        assert_eq!(w.push(b"1".to_vec()).await?, 1);
+        assert_eq!(w.push(b"2".to_vec()).await?, 2);
+        assert_eq!(w.durable_idx(), 0);
+        w.flush().await?;
+        assert_eq!(w.durable_idx(), 2);
+        assert!(matches!(w.wait_durable(3).await, Err(Error::InvalidIndex)));
+        w.close().await?;
+
+        options.sync = SyncPolicy::Entries(2);
+        let mut w = Wal::open_with(&path, options.clone()).await?;
+        assert_eq!(w.durable_idx(), 2);
+        assert_eq!(w.push(b"3".to_vec()).await?, 3);
+        assert_eq!(w.durable_idx(), 2);
+        assert_eq!(w.push(b"4".to_vec()).await?, 4);
+        assert_eq!(w.durable_idx(), 4);
+        w.close().await?;
+
+        options.sync = SyncPolicy::Interval(std::time::Duration::from_secs(3600));
+        let mut w = Wal::open_with(&path, options).await?;
+        assert_eq!(w.push(b"5".to_vec()).await?, 5);
+        assert_eq!(w.durable_idx(), 4);
+        w.wait_durable(5).await?;
+        assert_eq!(w.durable_idx(), 5);
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn push_batch() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let data = [b'A'; 100];
+        {
+            let mut w = Wal::open(&path, 512, 10).await?;
+            assert_eq!(w.push_batch(Vec::<Vec<u8>>::new()).await?, 1..1);
+            assert_eq!(w.push(b"1".to_vec()).await?, 1);
+            // the batch spans three chunks
+            let idxs = w.push_batch(vec![data.to_vec(); 10]).await?;
+            assert_eq!(idxs, 2..12);
+            assert_eq!(w.files.len(), 3);
+            assert_eq!(w.durable_idx(), 11);
+            assert_eq!(w.push(b"12".to_vec()).await?, 12);
+            w.close().await?;
+        }
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+        for idx in 2..12 {
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((idx, data.to_vec())));
+        }
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((12, b"12".to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn overflow() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // at most two chunks with a single entry each
+        let data = [b'A'; 100];
+        let mut w = Wal::open(&path, 128, 1).await?;
+        assert_eq!(w.push(data.to_vec()).await?, 1);
+        assert_eq!(w.push(data.to_vec()).await?, 2);
+        assert!(matches!(
+            w.push(data.to_vec()).await,
+            Err(Error::SizeExceeded)
+        ));
+        assert!(matches!(
+            w.push_batch(vec![data.to_vec(); 2]).await,
+            Err(Error::SizeExceeded)
+        ));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data.to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, data.to_vec())));
+        w.ack(2).await?;
+        // the rejected pushes wrote nothing
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        assert_eq!(w.push(data.to_vec()).await?, 3);
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((3, data.to_vec())));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn overflow_drop_oldest() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // at most two chunks with a single entry each
+        let data = |i: u8| vec![i; 100];
+        let mut options = Options::new(128, 1);
+        options.overflow = OverflowPolicy::DropOldest;
+        {
+            let mut w = Wal::open_with(&path, options.clone()).await?;
+            assert_eq!(w.push(data(1)).await?, 1);
+            assert_eq!(w.push(data(2)).await?, 2);
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data(1))));
+            // drops the chunk with the popped but unacknowledged entry
+            assert_eq!(w.push(data(3)).await?, 3);
+            assert_eq!(w.dropped(), 1);
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, data(2))));
+            w.ack(2).await?;
+            assert_eq!(w.push_batch(vec![data(4), data(5)]).await?, 4..6);
+            // entry 3 was not read yet
+            assert_eq!(w.dropped(), 2);
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((4, data(4))));
+            w.close().await?;
+        }
+        let mut w = Wal::open_with(&path, options).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((4, data(4))));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((5, data(5))));
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        assert_eq!(w.dropped(), 0);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn capacity_limits() -> Result<()> {
+        let data = [b'A'; 100];
+        {
+            let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+            let mut options = Options::new(1024, 10);
+            options.max_entries = Some(3);
+            let mut w = Wal::open_with(temp_dir.path(), options).await?;
+            assert_eq!(w.push_batch(vec![data.to_vec(); 3]).await?, 1..4);
+            assert_eq!(w.used_entries(), 3);
+            assert!(matches!(
+                w.push(data.to_vec()).await,
+                Err(Error::SizeExceeded)
+            ));
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data.to_vec())));
+            assert_eq!(w.used_entries(), 3);
+            w.ack(1).await?;
+            assert_eq!(w.used_entries(), 2);
+            assert_eq!(w.push(data.to_vec()).await?, 4);
+        }
+
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+        // two entries fill the first chunk, the next chunk is started right away
+        // entries are written with a timestamp
+        let entry = Record {
+            timestamp: Some(0),
+            ..Record::new(data.to_vec())
+        }
+        .size_on_disk();
+        let first_chunk = ChunkHeader::SIZE + 2 * entry;
+        let max_bytes = first_chunk + WalFile::rolled_over_size() + entry;
+        let mut options = Options::new(256, 10);
+        options.max_bytes = Some(max_bytes);
+        let used = {
+            let mut w = Wal::open_with(&path, options.clone()).await?;
+            assert_eq!(w.used_bytes(), ChunkHeader::SIZE);
+            assert_eq!(w.push_batch(vec![data.to_vec(); 3]).await?, 1..4);
+            assert_eq!(w.used_bytes(), max_bytes);
+            assert!(matches!(
+                w.push(b"1".to_vec()).await,
+                Err(Error::SizeExceeded)
+            ));
+            assert_eq!(w.pop_batch::<Vec<u8>>(3, 1024).await?.len(), 3);
+            // reclaims the first chunk
+            w.ack(3).await?;
+            assert_eq!(w.used_bytes(), max_bytes - first_chunk);
+            assert_eq!(w.push(data.to_vec()).await?, 4);
+            // dropped without closing so no ack record is appended
+            w.used_bytes()
+        };
+        let w = Wal::open_with(&path, options).await?;
+        assert_eq!(w.used_bytes(), used);
+        assert_eq!(w.used_entries(), 1);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn used_bytes_after_preserve_ack() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let on_disk = |w: &Wal| -> Result<u64> {
+            let mut len = 0;
+            for (_, file) in &w.files {
+                len += std::fs::metadata(file)?.len();
+            }
+            Ok(len)
+        };
+        // the third entry rolls the WAL over to a new chunk
+        let mut w = Wal::open(&path, 128, 1).await?;
+        let data = [b'A'; 64];
+        w.push(data.to_vec()).await?;
+        w.push(data.to_vec()).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data.to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, data.to_vec())));
+        w.ack(2).await?;
+        w.push(data.to_vec()).await?;
+        // the sealed chunk is not appended to, so its size stays the one counted
+        w.preserve_ack().await?;
+        assert_eq!(w.used_bytes(), on_disk(&w)?);
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((3, data.to_vec())));
+        w.ack(3).await?;
+        assert_eq!(w.used_bytes(), on_disk(&w)?);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn ttl() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // a single entry per chunk
+        let data = |i: u8| vec![i; 100];
+        let mut options = Options::new(128, 10);
+        options.ttl = Some(Duration::from_secs(3600));
+        {
+            let mut w = Wal::open_with(&path, options.clone()).await?;
+            assert_eq!(w.push(data(1)).await?, 1);
+            for i in 2..=4 {
+                w.push_with_ttl(data(i), Duration::ZERO).await?;
+            }
+            assert_eq!(w.push(data(5)).await?, 5);
+            assert_eq!(w.push_with_ttl(data(6), Duration::ZERO).await?, 6);
+            assert_eq!(w.push(data(7)).await?, 7);
+
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data(1))));
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((5, data(5))));
+            // acknowledging 1 acknowledges the expired entries after it
+            w.ack(1).await?;
+            assert_eq!(w.write_file.ack_idx, 4);
+            assert_eq!(w.files.first().map(|(idx, _)| *idx), Some(4));
+            w.ack(5).await?;
+            // expired entries are acknowledged as soon as they are skipped
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((7, data(7))));
+            assert_eq!(w.write_file.ack_idx, 6);
+            w.close().await?;
+        }
+        let mut w = Wal::open_with(&path, options).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((7, data(7))));
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn ttl_without_pop() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // a single entry per chunk and at most three chunks
+        let data = |i: u8| vec![i; 100];
+        let options = Options::new(128, 2);
+        {
+            let mut w = Wal::open_with(&path, options.clone()).await?;
+            // nothing is popped, the chunks are reclaimed because their entries expired
+            for i in 1..=10 {
+                assert_eq!(
+                    w.push_with_ttl(data(i), Duration::ZERO).await?,
+                    u64::from(i)
+                );
+            }
+            assert_eq!(w.write_file.ack_idx, 9);
+            assert_eq!(w.files.first().map(|(idx, _)| *idx), Some(10));
+            w.close().await?;
+        }
+        // the chunks are read to find out when their entries expire after a restart
+        let mut w = Wal::open_with(&path, options).await?;
+        assert_eq!(w.push(data(11)).await?, 11);
+        assert_eq!(w.push(data(12)).await?, 12);
+        assert_eq!(w.files.first().map(|(idx, _)| *idx), Some(11));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((11, data(11))));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn headers() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let headers = vec![
+            ("trace-id".to_string(), "42".to_string()),
+            ("source".to_string(), String::new()),
+        ];
+        let before = SystemTime::now() - Duration::from_millis(1);
+        {
+            let mut w = Wal::open(&path, 512, 10).await?;
+            assert_eq!(
+                w.push_with_headers(b"1".to_vec(), headers.clone()).await?,
+                1
+            );
+            assert_eq!(w.push(b"2".to_vec()).await?, 2);
+            let envelope = w.pop_envelope::<Vec<u8>>().await?.expect("entry");
+            assert_eq!(envelope.idx, 1);
+            assert_eq!(envelope.entry, b"1".to_vec());
+            assert_eq!(envelope.headers, headers);
+            w.close().await?;
+        }
+        let after = SystemTime::now();
+        let mut w = Wal::open(&path, 512, 10).await?;
+        // timestamps and headers survive a restart
+        let envelope = w.pop_envelope::<Vec<u8>>().await?.expect("entry");
+        assert_eq!(envelope.idx, 1);
+        assert_eq!(envelope.headers, headers);
+        let timestamp = envelope.timestamp.expect("timestamp");
+        assert!(before <= timestamp && timestamp <= after);
+        let envelope = w.pop_envelope::<Vec<u8>>().await?.expect("entry");
+        assert_eq!(envelope.idx, 2);
+        assert!(envelope.headers.is_empty());
+        assert_eq!(w.pop_envelope::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn pop_batch() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert!(w.pop_batch::<Vec<u8>>(10, 1024).await?.is_empty());
+        // spread the entries over three chunks
+        let data = [b'A'; 100];
+        assert_eq!(w.push_batch(vec![data.to_vec(); 10]).await?, 1..11);
+        assert_eq!(w.files.len(), 3);
+
+        let expected = |idxs: Range<u64>| idxs.map(|idx| (idx, data.to_vec())).collect::<Vec<_>>();
+        assert_eq!(w.pop_batch::<Vec<u8>>(3, 1024).await?, expected(1..4));
+        // the byte limit is soft, the entry reaching it is still popped
+        assert_eq!(w.pop_batch::<Vec<u8>>(10, 250).await?, expected(4..7));
+        assert_eq!(w.pop_batch::<Vec<u8>>(10, 1024).await?, expected(7..11));
+        assert!(w.pop_batch::<Vec<u8>>(10, 1024).await?.is_empty());
+
+        assert_eq!(w.push(b"11".to_vec()).await?, 11);
+        assert_eq!(
+            w.pop_batch::<Vec<u8>>(10, 1024).await?,
+            vec![(11, b"11".to_vec())]
+        );
+
+        w.revert().await?;
+        assert_eq!(w.pop_batch::<Vec<u8>>(10, 1024).await?, expected(1..11));
+        w.ack(10).await?;
+        assert_eq!(
+            w.pop_batch::<Vec<u8>>(10, 1024).await?,
+            vec![(11, b"11".to_vec())]
+        );
+        Ok(())
+    }
+
+    /// Entry that can only be read back if it is valid UTF-8
+    struct Text;
+
+    impl Entry for Text {
+        type Output = String;
+        type Error = std::string::FromUtf8Error;
+        fn serialize(self) -> std::result::Result<Vec<u8>, Self::Error> {
+            Ok(Vec::new())
+        }
+
+        fn deserialize(data: Vec<u8>) -> std::result::Result<Self::Output, Self::Error> {
+            String::from_utf8(data)
+        }
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn pop_batch_invalid_entry() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut w = Wal::open(&path, 512, 10).await?;
+        // the invalid entry starts the second chunk
+        let mut entries = vec![vec![b'A'; 100]; 6];
+        entries[4] = vec![0xff; 100];
+        assert_eq!(w.push_batch(entries).await?, 1..7);
+        assert_eq!(w.files[1].0, 5);
+
+        let text = String::from_utf8(vec![b'A'; 100]).expect("text");
+        let expected = |idxs: Range<u64>| idxs.map(|idx| (idx, text.clone())).collect::<Vec<_>>();
+        // the entries before the invalid one are not lost
+        assert_eq!(w.pop_batch::<Text>(10, 4096).await?, expected(1..5));
+        assert!(matches!(
+            w.pop_batch::<Text>(10, 4096).await,
+            Err(Error::IncompatibleError)
+        ));
+        assert_eq!(w.pop_batch::<Text>(10, 4096).await?, expected(6..7));
+
+        // the same goes for an invalid entry within a chunk
+        w.revert().await?;
+        assert_eq!(w.pop_batch::<Text>(3, 4096).await?, expected(1..4));
+        w.ack(3).await?;
+        w.push_batch(vec![vec![0xff; 1], b"B".to_vec()]).await?;
+        assert_eq!(w.pop_batch::<Text>(10, 4096).await?, expected(4..5));
+        assert!(w.pop_batch::<Text>(10, 4096).await.is_err());
+        assert_eq!(w.pop_batch::<Text>(10, 4096).await?, expected(6..7));
+        assert!(w.pop_batch::<Text>(10, 4096).await.is_err());
+        assert_eq!(
+            w.pop_batch::<Text>(10, 4096).await?,
+            vec![(8, "B".to_string())]
+        );
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn peek() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert_eq!(w.peek::<Vec<u8>>().await?, None);
+        // spread the entries over four chunks
+        let data = |i: u8| vec![i; 100];
+        assert_eq!(w.push_batch((1..=10).map(data)).await?, 1..11);
+        assert_eq!(w.push_with_ttl(data(11), Duration::ZERO).await?, 11);
+        assert_eq!(w.push(data(12)).await?, 12);
+        assert_eq!(w.files.len(), 4);
+
+        let expected =
+            |idxs: Range<u64>| idxs.map(|idx| (idx, data(idx as u8))).collect::<Vec<_>>();
+        assert_eq!(w.peek::<Vec<u8>>().await?, Some((1, data(1))));
+        assert_eq!(w.peek::<Vec<u8>>().await?, Some((1, data(1))));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data(1))));
+        // peeking crosses chunks and skips expired entries
+        let mut all = expected(2..11);
+        all.push((12, data(12)));
+        assert_eq!(w.peek_n::<Vec<u8>>(3).await?, expected(2..5));
+        assert_eq!(w.peek_n::<Vec<u8>>(20).await?, all);
+        assert_eq!(w.pop_batch::<Vec<u8>>(4, 1024).await?, expected(2..6));
+        assert_eq!(w.peek_n::<Vec<u8>>(20).await?, all[4..].to_vec());
+        assert_eq!(w.pop_batch::<Vec<u8>>(20, 4096).await?, all[4..].to_vec());
+        assert!(w.peek_n::<Vec<u8>>(20).await?.is_empty());
+
+        // after a revert the reverted entries are peeked again
+        w.ack(3).await?;
+        w.revert().await?;
+        assert_eq!(w.peek_n::<Vec<u8>>(2).await?, expected(4..6));
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((4, data(4))));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn get() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert!(matches!(
+            w.get::<Vec<u8>>(1).await,
+            Err(Error::IndexNotWritten {
+                idx: 1,
+                next_idx: 1
+            })
+        ));
+        // spread the entries over three chunks
+        let data = |i: u8| vec![i; 100];
+        assert_eq!(w.push_batch((1..=10).map(data)).await?, 1..11);
+        assert_eq!(w.push_with_ttl(data(11), Duration::ZERO).await?, 11);
+        assert_eq!(w.files.len(), 3);
+        let second = w.files[1].0;
+
+        // reclaim the first chunk and read into the second one
+        for _ in 1..=second {
+            w.pop::<Vec<u8>>().await?;
+        }
+        w.ack(second).await?;
+        assert_eq!(w.files.first().map(|(idx, _)| *idx), Some(second));
+        // the index of the reclaimed chunk is deleted with it
+        assert_eq!(std::fs::read_dir(&path)?.count(), 2 * w.files.len());
+
+        assert!(matches!(
+            w.get::<Vec<u8>>(1).await,
+            Err(Error::IndexReclaimed { idx: 1, first_idx }) if first_idx == second
+        ));
+        assert!(matches!(
+            w.get::<Vec<u8>>(12).await,
+            Err(Error::IndexNotWritten {
+                idx: 12,
+                next_idx: 12
+            })
+        ));
+        assert!(matches!(
+            w.get::<Vec<u8>>(0).await,
+            Err(Error::IndexNotWritten {
+                idx: 0,
+                next_idx: 12
+            })
+        ));
+        // acknowledged, popped, upcoming and expired entries can be read
+        for idx in second..=11 {
+            assert_eq!(w.get::<Vec<u8>>(idx).await?, data(idx as u8));
+        }
+        assert_eq!(
+            w.pop::<Vec<u8>>().await?,
+            Some((second + 1, data(second as u8 + 1)))
+        );
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn torn_write() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+
+        let path = temp_dir.path().to_path_buf();
+        {
+            let mut w = Wal::open(&path, 1024, 10).await?;
+            assert_eq!(w.recovery(), Recovery::default());
+            assert_eq!(w.push(b"1".to_vec()).await?, 1);
+            assert_eq!(w.push(b"22".to_vec()).await?, 2);
+            // the process dies while writing the second entry
+        }
+        let chunk = path.join(Wal::format_file_name(0));
+        let len = std::fs::metadata(&chunk)?.len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&chunk)?
+            .set_len(len - 1)?;
+
+        let mut w = Wal::open(&path, 1024, 10).await?;
+        assert_eq!(w.recovery().records, 1);
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, b"1".to_vec())));
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        assert_eq!(w.push(b"22".to_vec()).await?, 2);
+        assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, b"22".to_vec())));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn crash_after_rollover() -> Result<()> {
+        // empty, partial header and header only chunks
+        for len in [0, 10, 24] {
+            let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+
+            let path = temp_dir.path().to_path_buf();
+            let data = [b'A'; 100];
+            {
+                let mut w = Wal::open(&path, 128, 10).await?;
+                assert_eq!(w.push(data.to_vec()).await?, 1);
+                assert_eq!(w.pop::<Vec<u8>>().await?, Some((1, data.to_vec())));
+                w.ack(1).await?;
+                assert_eq!(w.push(data.to_vec()).await?, 2);
+                // the process dies right after the new chunk was created
+            }
+            let chunk = path.join(Wal::format_file_name(3));
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&chunk)?
+                .set_len(len)?;
+
+            let mut w = Wal::open(&path, 128, 10).await?;
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, data.to_vec())));
+            assert_eq!(w.pop::<Vec<u8>>().await?, None);
+            assert_eq!(w.push(data.to_vec()).await?, 3);
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((3, data.to_vec())));
+            w.close().await?;
+
+            let mut w = Wal::open(&path, 128, 10).await?;
+            assert_eq!(w.pop::<Vec<u8>>().await?, Some((2, data.to_vec())));
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn foreign_file() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+
+        let path = temp_dir.path().to_path_buf();
+        std::fs::write(path.join("42"), b"definitely not a WAL chunk")?;
+        assert!(matches!(
+            Wal::open(&path, 128, 10).await,
+            Err(Error::InvalidFile)
+        ));
+
+        // a length far beyond the end of the file is not allocated
+        let mut bytes = vec![0u8; 64];
+        bytes[3] = 0x10;
+        std::fs::write(path.join("42"), bytes)?;
+        assert!(matches!(
+            Wal::open(&path, 128, 10).await,
+            Err(Error::InvalidFile)
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,118 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+/// Options to open a [`Wal`](crate::Wal) with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Soft limit of bytes per chunk, chunks are cycled once an entry is appended and the
+    /// chunk now exceeds the limit.
+    pub chunk_size: u64,
+    /// Soft limit of the number of chunks, see [`Wal::open`](crate::Wal::open).
+    pub max_chunks: usize,
+    /// When entries written by `push` are synced to disk
+    pub sync: SyncPolicy,
+    /// Hard limit of bytes all chunks take up on disk, a `push` that would exceed it is
+    /// handled according to the overflow policy. Acknowledgements persisted on `close` or
+    /// with `preserve_ack` are not limited.
+    pub max_bytes: Option<u64>,
+    /// Hard limit of entries that are pushed but not acknowledged yet, a `push` that would
+    /// exceed it is handled according to the overflow policy.
+    pub max_entries: Option<u64>,
+    /// What `push` does when the WAL exceeds its limits
+    pub overflow: OverflowPolicy,
+    /// Time after which entries expire unless they were pushed with their own TTL. Expired
+    /// entries are skipped by `pop` and count as acknowledged.
+    pub ttl: Option<Duration>,
+}
+
+impl Options {
+    /// Options with the given limits and the default for everything else
+    #[must_use]
+    pub fn new(chunk_size: u64, max_chunks: usize) -> Self {
+        Self {
+            chunk_size,
+            max_chunks,
+            sync: SyncPolicy::default(),
+            max_bytes: None,
+            max_entries: None,
+            overflow: OverflowPolicy::default(),
+            ttl: None,
+        }
+    }
+}
+
+/// Durability policy for entries written by `push`.
+///
+/// An entry that has been synced survives a crash of the process as well as of the operating
+/// system. Entries that are not synced yet survive a crash of the process but might be lost
+/// if the operating system crashes or the machine loses power.
+///
+/// Independent of the policy the WAL is synced when a chunk is full, when acks are persisted
+/// and when it is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Every `push` syncs before it returns, an entry is durable once `push` returned.
+    ///
+    /// This is the safest and the slowest policy.
+    #[default]
+    Always,
+    /// Syncs on every `n`th `push`, up to `n - 1` entries can be lost.
+    Entries(u64),
+    /// Syncs on the first `push` after the given duration passed since the last sync. There
+    /// is no background timer, entries pushed before a quiet period stay unsynced until the
+    /// next `push`, `flush` or `close`.
+    Interval(Duration),
+    /// Never syncs on `push`, `Wal::flush` has to be called to make entries durable.
+    Never,
+}
+
+/// What a `push` does when the WAL exceeds its limits.
+///
+/// Unless the oldest entries are dropped to make room, an entry that does not fit into the WAL
+/// is never written, for a batch either all of its entries are written or none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Fail with [`Error::SizeExceeded`](crate::Error::SizeExceeded).
+    #[default]
+    Reject,
+    /// Wait until acknowledgements free enough capacity for the entries, failing with
+    /// [`Error::SizeExceeded`](crate::Error::SizeExceeded) if that takes longer than the
+    /// timeout. Entries that exceed the limits even of an empty WAL fail right away.
+    ///
+    /// Only pushes through a [`SharedWal`](crate::SharedWal) or its producers can wait, as
+    /// acknowledging requires access to the WAL. [`Wal::push`](crate::Wal::push) rejects.
+    Block(Option<Duration>),
+    /// Drop the oldest chunks to make room for the entries, advancing the ack index past them.
+    /// The chunk currently written to is never dropped, entries that still exceed the limits
+    /// are rejected.
+    ///
+    /// Entries in the dropped chunks that were not acknowledged yet are lost and counted by
+    /// [`Wal::dropped`](crate::Wal::dropped). A batch that exceeds the WAL's capacity on its
+    /// own drops its first entries.
+    DropOldest,
+}
+
+impl SyncPolicy {
+    /// Checks if a sync is due with `unsynced` entries written since the sync at `last_sync`
+    pub(crate) fn is_due(self, unsynced: u64, last_sync: Instant) -> bool {
+        match self {
+            SyncPolicy::Always => unsynced > 0,
+            SyncPolicy::Entries(n) => unsynced >= n.max(1),
+            SyncPolicy::Interval(d) => unsynced > 0 && last_sync.elapsed() >= d,
+            SyncPolicy::Never => false,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{deserialize, file::Record, Entry, Result, Wal};
+use std::{collections::VecDeque, marker::PhantomData, ops::Range};
+
+/// An async iterator over the entries of a [`Wal`] in a range of indexes, created by
+/// [`Wal::range`].
+///
+/// Entries are read chunk by chunk, independent of the read position of the WAL. The WAL is
+/// borrowed for as long as the iterator exists, so no chunk can be reclaimed while it is read.
+pub struct WalRange<'wal, E> {
+    wal: &'wal mut Wal,
+    next: u64,
+    end: u64,
+    buffer: VecDeque<(u64, Record)>,
+    entry: PhantomData<fn(E)>,
+}
+
+impl Wal {
+    /// Iterates over all entries in `idxs` that are still stored in the write-ahead-log,
+    /// including entries that were popped, acknowledged or expired already.
+    ///
+    /// Entries whose chunk was reclaimed and indexes that were not written yet are not part
+    /// of the iteration.
+    pub fn range<E>(&mut self, idxs: Range<u64>) -> WalRange<'_, E>
+    where
+        E: Entry,
+    {
+        WalRange {
+            wal: self,
+            next: idxs.start,
+            end: idxs.end,
+            buffer: VecDeque::new(),
+            entry: PhantomData,
+        }
+    }
+}
+
+impl<E> WalRange<'_, E>
+where
+    E: Entry,
+{
+    /// Reads the next entry of the range, returns `None` once all entries are read
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn next(&mut self) -> Result<Option<(u64, E::Output)>> {
+        if self.buffer.is_empty() {
+            self.read_chunk().await?;
+        }
+        match self.buffer.pop_front() {
+            Some((idx, record)) => Ok(Some((idx, deserialize::<E>(record.data)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the rest of the range from the chunk holding the next index into the buffer
+    async fn read_chunk(&mut self) -> Result<()> {
+        let end = self.end.min(self.wal.write_file.next_idx_to_write);
+        self.next = self.next.max(self.wal.first_idx());
+        while self.buffer.is_empty() && self.next < end {
+            let mut records = Vec::new();
+            let n = usize::try_from(end - self.next).unwrap_or(usize::MAX);
+            let next = self.wal.read_at(self.next, n, &mut records).await?;
+            // no progress means there are no further entries
+            self.next = if next > self.next { next } else { end };
+            self.buffer
+                .extend(records.into_iter().filter(|(idx, _)| *idx < end));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::Builder as TempDirBuilder;
+
+    async fn collect(range: &mut WalRange<'_, Vec<u8>>) -> Result<Vec<u64>> {
+        let mut idxs = Vec::new();
+        while let Some((idx, entry)) = range.next().await? {
+            assert_eq!(entry, vec![idx as u8; 100]);
+            idxs.push(idx);
+        }
+        Ok(idxs)
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn range() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut w = Wal::open(&path, 512, 10).await?;
+        assert!(collect(&mut w.range(0..10)).await?.is_empty());
+        // spread the entries over three chunks
+        let data = |i: u8| vec![i; 100];
+        assert_eq!(w.push_batch((1..=10).map(data)).await?, 1..11);
+        assert_eq!(w.push_with_ttl(data(11), Duration::ZERO).await?, 11);
+        assert_eq!(w.files.len(), 3);
+        let second = w.files[1].0;
+
+        assert_eq!(
+            collect(&mut w.range(0..100)).await?,
+            (1..12).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            collect(&mut w.range(3..7)).await?,
+            (3..7).collect::<Vec<_>>()
+        );
+        assert!(collect(&mut w.range(5..5)).await?.is_empty());
+
+        // reclaim the first chunk, popped and acknowledged entries are still in the range
+        for _ in 1..=second {
+            w.pop::<Vec<u8>>().await?;
+        }
+        w.ack(second).await?;
+        assert_eq!(
+            collect(&mut w.range(1..10)).await?,
+            (second..10).collect::<Vec<_>>()
+        );
+        // the read position is left untouched
+        assert_eq!(
+            w.pop::<Vec<u8>>().await?,
+            Some((second + 1, data(second as u8 + 1)))
+        );
+        Ok(())
+    }
+}
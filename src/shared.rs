@@ -0,0 +1,494 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    commit::GroupCommit, file::Record, match_error, serialize, Entry, Envelope, Error, Headers,
+    OverflowPolicy, Result, SyncPolicy, Wal,
+};
+#[cfg(feature = "async-std")]
+use async_std::{
+    future::timeout,
+    sync::{Mutex, MutexGuard},
+};
+use event_listener::{Event, EventListener};
+use std::{
+    collections::VecDeque,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "tokio")]
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::timeout,
+};
+
+/// A handle to a [`Wal`] that can be cloned and shared between tasks.
+///
+/// With the default [`SyncPolicy::Always`] pushes are group committed: the `Wal` is only
+/// locked while an entry is written, not while it is synced. Entries pushed while a sync is
+/// in flight are made durable together by the next sync, so concurrent producers share syncs
+/// instead of waiting for each other's. As with [`Wal::push`] a push only returns once its
+/// entry is durable.
+///
+/// With any other policy pushes sync according to the policy as [`Wal::push`] does.
+///
+/// Consumers can wait for new entries with [`SharedWal::pop_wait`] instead of polling `pop`.
+#[derive(Clone)]
+pub struct SharedWal {
+    pub(crate) wal: Arc<Mutex<Wal>>,
+    pub(crate) commit: Arc<GroupCommit>,
+    pub(crate) pushed: Arc<Event>,
+    pub(crate) reclaimed: Arc<Event>,
+}
+
+/// Waits for chunks to be reclaimed, if `limit` is set this fails with `SizeExceeded` once
+/// `limit` passed since the first wait
+async fn wait_reclaimed(
+    reclaimed: EventListener,
+    limit: Option<Duration>,
+    deadline: &mut Option<Instant>,
+) -> Result<()> {
+    let Some(limit) = limit else {
+        reclaimed.await;
+        return Ok(());
+    };
+    let deadline = *deadline.get_or_insert_with(|| Instant::now() + limit);
+    timeout(
+        deadline.saturating_duration_since(Instant::now()),
+        reclaimed,
+    )
+    .await
+    .map_err(|_| Error::SizeExceeded)
+}
+
+impl Wal {
+    /// Turns the WAL into a handle that can be shared between tasks
+    #[must_use]
+    pub fn into_shared(self) -> SharedWal {
+        SharedWal {
+            commit: self.commit.clone(),
+            pushed: self.pushed.clone(),
+            reclaimed: self.reclaimed.clone(),
+            wal: Arc::new(Mutex::new(self)),
+        }
+    }
+}
+
+impl SharedWal {
+    /// Push a new entry into the write-ahead-log, see [`Wal::push`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push<E>(&self, data: E) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        Ok(self.push_batch(std::iter::once(data)).await?.start)
+    }
+
+    /// Push a new entry into the write-ahead-log that expires after `ttl`, see
+    /// [`Wal::push_with_ttl`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push_with_ttl<E>(
+        &self,
+        data: E,
+        ttl: Duration,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        let entries = serialize(std::iter::once(data), Some(ttl))?;
+        let idxs = self.push_records(entries).await.map_err(match_error)?;
+        Ok(idxs.start)
+    }
+
+    /// Push a new entry with `headers` into the write-ahead-log, see
+    /// [`Wal::push_with_headers`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push_with_headers<E>(
+        &self,
+        data: E,
+        headers: Headers,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        let mut entries = serialize(std::iter::once(data), None)?;
+        entries
+            .iter_mut()
+            .for_each(|r| r.headers.clone_from(&headers));
+        let idxs = self.push_records(entries).await.map_err(match_error)?;
+        Ok(idxs.start)
+    }
+
+    /// Push a batch of entries into the write-ahead-log, see [`Wal::push_batch`].
+    ///
+    /// With [`OverflowPolicy::Block`] it waits until acknowledged chunks are reclaimed if the
+    /// entries exceed the WAL's capacity.
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entries exceed the WAL's capacity
+    pub async fn push_batch<E, I>(
+        &self,
+        entries: I,
+    ) -> std::result::Result<Range<u64>, Error<E::Error>>
+    where
+        E: Entry,
+        I: IntoIterator<Item = E>,
+    {
+        let entries = serialize(entries, None)?;
+        self.push_records(entries).await.map_err(match_error)
+    }
+
+    /// Writes serialized entries, waiting for capacity according to the overflow policy
+    async fn push_records(&self, mut entries: VecDeque<Record>) -> Result<Range<u64>> {
+        let mut deadline = None;
+        loop {
+            // listen before writing so a reclaim in between is not missed
+            let reclaimed = self.reclaimed.listen();
+            let mut wal = self.wal.lock().await;
+            match wal.write_batch(&mut entries, false).await {
+                Ok(idxs) => {
+                    if wal.options.sync == SyncPolicy::Always {
+                        drop(wal);
+                        if !idxs.is_empty() {
+                            self.commit.wait_durable(idxs.end - 1).await?;
+                        }
+                    } else {
+                        wal.sync_if_due().await?;
+                    }
+                    return Ok(idxs);
+                }
+                Err(Error::SizeExceeded) => {
+                    let OverflowPolicy::Block(limit) = wal.options.overflow else {
+                        return Err(Error::SizeExceeded);
+                    };
+                    // waiting does not help if the entries do not even fit into an empty WAL
+                    if wal.fitting_when_reclaimed(&entries) < entries.len() {
+                        return Err(Error::SizeExceeded);
+                    }
+                    drop(wal);
+                    wait_reclaimed(reclaimed, limit, &mut deadline).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Pop an existing entry from the write-ahead-log, see [`Wal::pop`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop<E>(&self) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.lock().await.pop::<E>().await
+    }
+
+    /// Pop an existing entry with its metadata from the write-ahead-log, see
+    /// [`Wal::pop_envelope`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_envelope<E>(&self) -> Result<Option<Envelope<E::Output>>>
+    where
+        E: Entry,
+    {
+        self.wal.lock().await.pop_envelope::<E>().await
+    }
+
+    /// Returns the next entry without popping it, see [`Wal::peek`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn peek<E>(&self) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.lock().await.peek::<E>().await
+    }
+
+    /// Returns up to `n` upcoming entries without popping them, see [`Wal::peek_n`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn peek_n<E>(&self, n: usize) -> Result<Vec<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.lock().await.peek_n::<E>(n).await
+    }
+
+    /// Reads the entry at `idx` without popping it, see [`Wal::get`].
+    ///
+    /// ## Errors
+    /// see [`Wal::get`]
+    pub async fn get<E>(&self, idx: u64) -> Result<E::Output>
+    where
+        E: Entry,
+    {
+        self.wal.lock().await.get::<E>(idx).await
+    }
+
+    /// Pop an entry from the write-ahead-log, waiting for one to be pushed if the WAL is empty.
+    ///
+    /// Cancelling the returned future, for example by dropping it in a `select!`, does not
+    /// lose entries: an entry is only popped when the future completes.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_wait<E>(&self) -> Result<(u64, E::Output)>
+    where
+        E: Entry,
+    {
+        loop {
+            // listen before checking so a push in between is not missed
+            let listener = self.pushed.listen();
+            if let Some(entry) = self.pop::<E>().await? {
+                return Ok(entry);
+            }
+            listener.await;
+        }
+    }
+
+    /// Pop an entry from the write-ahead-log, waiting up to `duration` for one to be pushed if
+    /// the WAL is empty. Returns `None` if no entry was pushed in time.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_timeout<E>(&self, duration: Duration) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        match timeout(duration, self.pop_wait::<E>()).await {
+            Ok(entry) => entry.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Pop up to `max_entries` existing entries from the write-ahead-log, see
+    /// [`Wal::pop_batch`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_batch<E>(
+        &self,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Vec<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal
+            .lock()
+            .await
+            .pop_batch::<E>(max_entries, max_bytes)
+            .await
+    }
+
+    /// Acknowledges an entry as completely processed, see [`Wal::ack`].
+    ///
+    /// ## Errors
+    /// see [`Wal::ack`]
+    pub async fn ack(&self, id: u64) -> Result<()> {
+        self.wal.lock().await.ack(id).await
+    }
+
+    /// Reverts the read index back to the last acknowledged index, see [`Wal::revert`].
+    ///
+    /// ## Errors
+    /// on IO Errors or invalid WAL files
+    pub async fn revert(&self) -> Result<()> {
+        self.wal.lock().await.revert().await
+    }
+
+    /// Syncs all entries written so far to disk, see [`Wal::flush`].
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn flush(&self) -> Result<()> {
+        self.wal.lock().await.flush().await
+    }
+
+    /// The highest index that is synced to disk
+    #[must_use]
+    pub fn durable_idx(&self) -> u64 {
+        self.commit.durable()
+    }
+
+    /// Number of entries dropped by the overflow policy, see [`Wal::dropped`].
+    pub async fn dropped(&self) -> u64 {
+        self.wal.lock().await.dropped()
+    }
+
+    /// Locks the WAL for operations that are not available on the shared handle
+    pub async fn lock(&self) -> MutexGuard<'_, Wal> {
+        self.wal.lock().await
+    }
+
+    /// Persists the ack index, see [`Wal::close`]. Other handles to the WAL stay usable.
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn close(self) -> Result<()> {
+        self.wal.lock().await.preserve_ack().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test::spawn, Options};
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    async fn group_commit() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let wal = Wal::open(&path, 512, 100).await?.into_shared();
+        let producers: Vec<_> = (0..8)
+            .map(|p| {
+                let wal = wal.clone();
+                spawn(async move {
+                    let mut pushed = Vec::new();
+                    for i in 0..12 {
+                        let idx = wal.push(vec![p, i]).await?;
+                        // the entry has to be durable once push returned
+                        assert!(wal.durable_idx() >= idx);
+                        pushed.push(idx);
+                    }
+                    let idxs = wal.push_batch(vec![vec![p]; 4]).await?;
+                    assert!(wal.durable_idx() >= idxs.end - 1);
+                    pushed.extend(idxs);
+                    Ok::<_, Error<_>>(pushed)
+                })
+            })
+            .collect();
+        let mut pushed = Vec::new();
+        for producer in producers {
+            pushed.extend(producer.await?);
+        }
+        pushed.sort_unstable();
+        assert_eq!(pushed, (1..=128).collect::<Vec<_>>());
+        assert_eq!(wal.durable_idx(), 128);
+
+        for idx in 1..=128 {
+            let (popped, _) = wal.pop::<Vec<u8>>().await?.expect("entry");
+            assert_eq!(popped, idx);
+        }
+        assert_eq!(wal.pop::<Vec<u8>>().await?, None);
+        wal.close().await?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    async fn pop_wait() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let wal = Wal::open(&path, 512, 100).await?.into_shared();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(wal.pop_timeout::<Vec<u8>>(timeout).await?, None);
+
+        let consumer = {
+            let wal = wal.clone();
+            spawn(async move {
+                let mut popped = Vec::new();
+                for _ in 0..20 {
+                    popped.push(wal.pop_wait::<Vec<u8>>().await?);
+                }
+                Ok::<_, Error<_>>(popped)
+            })
+        };
+        for i in 0..20 {
+            wal.push(vec![i]).await?;
+        }
+        let popped = consumer.await?;
+        let expected: Vec<_> = (0..20).map(|i| (u64::from(i) + 1, vec![i])).collect();
+        assert_eq!(popped, expected);
+
+        assert_eq!(wal.pop_timeout::<Vec<u8>>(timeout).await?, None);
+        wal.push(b"21".to_vec()).await?;
+        assert_eq!(
+            wal.pop_timeout::<Vec<u8>>(timeout).await?,
+            Some((21, b"21".to_vec()))
+        );
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    async fn overflow_block() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // at most two chunks with a single entry each
+        let data = [b'A'; 100];
+        let mut options = Options::new(128, 1);
+        options.overflow = OverflowPolicy::Block(Some(Duration::from_millis(200)));
+        let wal = Wal::open_with(&path, options).await?.into_shared();
+        assert_eq!(wal.push(data.to_vec()).await?, 1);
+        assert_eq!(wal.push(data.to_vec()).await?, 2);
+        // nothing is reclaimed within the timeout
+        assert!(matches!(
+            wal.push(data.to_vec()).await,
+            Err(Error::SizeExceeded)
+        ));
+
+        let producer = {
+            let wal = wal.clone();
+            spawn(async move { wal.push(data.to_vec()).await })
+        };
+        assert_eq!(wal.pop::<Vec<u8>>().await?, Some((1, data.to_vec())));
+        assert_eq!(wal.pop::<Vec<u8>>().await?, Some((2, data.to_vec())));
+        wal.ack(2).await?;
+        // the blocked push continues once the acknowledged chunk is reclaimed
+        assert_eq!(producer.await?, 3);
+        assert_eq!(wal.pop::<Vec<u8>>().await?, Some((3, data.to_vec())));
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn overflow_block_never_fits() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut options = Options::new(128, 10);
+        options.max_bytes = Some(256);
+        options.max_entries = Some(2);
+        options.overflow = OverflowPolicy::Block(None);
+        let wal = Wal::open_with(&path, options).await?.into_shared();
+        // entries that exceed the limits of an empty WAL fail instead of waiting forever
+        assert!(matches!(
+            wal.push(vec![b'A'; 512]).await,
+            Err(Error::SizeExceeded)
+        ));
+        assert!(matches!(
+            wal.push_batch((0..3u8).map(|i| vec![i])).await,
+            Err(Error::SizeExceeded)
+        ));
+        assert_eq!(wal.push_batch((0..2u8).map(|i| vec![i])).await?, 1..3);
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{file::Record, match_error, Entry, Error, Result, SharedWal};
+use futures_sink::Sink;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+type Flush = Pin<Box<dyn Future<Output = (VecDeque<Record>, Result<()>)> + Send>>;
+
+/// A [`Sink`] pushing entries into a [`SharedWal`], created by [`SharedWal::sink`].
+///
+/// Entries are serialized and buffered by `start_send` and written as a batch and synced once
+/// the sink is flushed or the buffer is full. A flush only completes once all buffered entries
+/// are durable, independent of the WAL's [`SyncPolicy`](crate::SyncPolicy).
+///
+/// When the WAL exceeds its limits the flush writes the entries that fit and waits until
+/// acknowledged chunks are reclaimed for the rest instead of failing with
+/// [`Error::SizeExceeded`]. It only fails with it if an entry does not even fit into an empty
+/// WAL.
+pub struct WalSink<E> {
+    wal: SharedWal,
+    capacity: usize,
+    buffer: VecDeque<Record>,
+    flush: Option<Flush>,
+    entry: PhantomData<fn(E)>,
+}
+
+impl SharedWal {
+    /// A sink pushing entries into the write-ahead-log that buffers up to `capacity` entries
+    /// before they are flushed.
+    #[must_use]
+    pub fn sink<E>(&self, capacity: usize) -> WalSink<E>
+    where
+        E: Entry,
+    {
+        WalSink {
+            wal: self.clone(),
+            capacity: capacity.max(1),
+            buffer: VecDeque::with_capacity(capacity),
+            flush: None,
+            entry: PhantomData,
+        }
+    }
+}
+
+impl<E> WalSink<E> {
+    /// The WAL the entries are pushed into
+    #[must_use]
+    pub fn wal(&self) -> &SharedWal {
+        &self.wal
+    }
+}
+
+impl<E> Sink<E> for WalSink<E>
+where
+    E: Entry,
+{
+    type Error = Error<E::Error>;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        if self.flush.is_some() || self.buffer.len() >= self.capacity {
+            self.poll_flush(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: E) -> std::result::Result<(), Self::Error> {
+        let data = item.serialize().map_err(Error::Entry)?;
+        self.buffer.push_back(Record::new(data));
+        Ok(())
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        let this = &mut *self;
+        if this.flush.is_none() && this.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        let flush = this.flush.get_or_insert_with(|| {
+            let buffer = std::mem::take(&mut this.buffer);
+            Box::pin(write(this.wal.clone(), buffer))
+        });
+        let (buffer, result) = ready!(flush.as_mut().poll(cx));
+        this.flush = None;
+        this.buffer = buffer;
+        Poll::Ready(result.map_err(match_error))
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Writes the buffered entries and waits until they are durable, returns the entries that
+/// could not be written.
+async fn write(wal: SharedWal, mut buffer: VecDeque<Record>) -> (VecDeque<Record>, Result<()>) {
+    let mut last = None;
+    while !buffer.is_empty() {
+        // listen before writing so a reclaim in between is not missed
+        let reclaimed = wal.reclaimed.listen();
+        let mut w = wal.wal.lock().await;
+        let start = w.write_file.next_idx_to_write;
+        let written = w.write_batch(&mut buffer, true).await;
+        if w.write_file.next_idx_to_write > start {
+            last = Some(w.write_file.next_idx_to_write - 1);
+        }
+        match written {
+            Ok(_) => {}
+            // waiting does not help if the next entry does not even fit into an empty WAL
+            Err(Error::SizeExceeded) if w.fitting_when_reclaimed(&buffer) == 0 => {
+                return (buffer, Err(Error::SizeExceeded));
+            }
+            Err(Error::SizeExceeded) => {
+                drop(w);
+                reclaimed.await;
+            }
+            Err(e) => return (buffer, Err(e)),
+        }
+    }
+    let result = match last {
+        Some(idx) => wal.commit.wait_durable(idx).await,
+        None => Ok(()),
+    };
+    (buffer, result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Options, Wal};
+    use futures::{stream, SinkExt, StreamExt};
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn sink() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let wal = Wal::open(&path, 512, 100).await?.into_shared();
+        let mut sink = wal.sink::<Vec<u8>>(4);
+        stream::iter(0..10)
+            .map(|i| Ok(vec![i]))
+            .forward(&mut sink)
+            .await
+            .map_err(match_error)?;
+        assert_eq!(wal.durable_idx(), 10);
+        for i in 0..10 {
+            assert_eq!(
+                wal.pop::<Vec<u8>>().await?,
+                Some((u64::from(i) + 1, vec![i]))
+            );
+        }
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn backpressure() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // at most two chunks with a single entry each
+        let wal = Wal::open(&path, 128, 1).await?.into_shared();
+        let data = [b'A'; 100];
+        let mut sink = wal.sink::<Vec<u8>>(8);
+        for _ in 0..6 {
+            sink.feed(data.to_vec()).await.map_err(match_error)?;
+        }
+        let consumer = async {
+            let mut popped = Vec::new();
+            while popped.len() < 6 {
+                let (idx, entry) = wal.pop_wait::<Vec<u8>>().await?;
+                assert_eq!(entry, data.to_vec());
+                wal.ack(idx).await?;
+                popped.push(idx);
+            }
+            Ok::<_, Error<_>>(popped)
+        };
+        // the flush waits for the consumer to reclaim chunks instead of failing
+        let (flushed, popped) = futures::join!(sink.flush(), consumer);
+        flushed.map_err(match_error)?;
+        assert_eq!(popped?, (1..=6).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn never_fits() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut options = Options::new(128, 10);
+        options.max_bytes = Some(256);
+        let wal = Wal::open_with(&path, options).await?.into_shared();
+        let mut sink = wal.sink::<Vec<u8>>(8);
+        sink.feed(vec![b'A'; 8]).await.map_err(match_error)?;
+        sink.feed(vec![b'B'; 512]).await.map_err(match_error)?;
+        // the entry fits into no WAL at all, so waiting for a reclaim would never end
+        assert!(matches!(sink.flush().await, Err(Error::SizeExceeded)));
+        assert_eq!(wal.pop::<Vec<u8>>().await?, Some((1, vec![b'A'; 8])));
+        assert_eq!(wal.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{file::Record, match_error, Entry, Error, Options, Result, Wal};
+#[cfg(feature = "async-std")]
+use async_std::path::Path;
+use std::collections::VecDeque;
+#[cfg(feature = "tokio")]
+use std::path::Path;
+
+/// A queue that keeps entries in memory and only spills them to a [`Wal`] when it runs out
+/// of memory or is closed.
+///
+/// Up to `capacity` entries are kept in memory. When a push exceeds it, all entries in memory
+/// are spilled to the WAL with a single [`Wal::push_batch`]. Entries are always popped in the
+/// order they were pushed, spilled entries are read back from the WAL before the ones in
+/// memory.
+///
+/// ## Durability
+///
+/// - Entries in memory are **not** durable, they are lost if the process crashes or the queue
+///   is dropped without calling [`SpillQueue::close`] or [`SpillQueue::flush`].
+/// - Spilled entries are as durable as entries pushed to the WAL with its
+///   [`SyncPolicy`](crate::SyncPolicy).
+/// - Popping a spilled entry acknowledges it. As with [`Wal::ack`] acknowledgements are only
+///   persisted with the next spill or on close, so after a crash popped entries can be popped
+///   again.
+/// - On restart the entries spilled before are popped first, in order.
+pub struct SpillQueue {
+    wal: Wal,
+    memory: VecDeque<Record>,
+    capacity: usize,
+    /// The WAL might hold entries that were not popped yet
+    spilled: bool,
+}
+
+impl SpillQueue {
+    /// Opens a spill queue that keeps up to `capacity` entries in memory and spills to the WAL
+    /// in `path`, see [`Wal::open_with`].
+    ///
+    /// ## Errors
+    /// Errors if `path` isn't an existing directory, it has content that isn't a valid Wal.
+    pub async fn open<P>(path: P, capacity: usize, options: Options) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(Self {
+            wal: Wal::open_with(path, options).await?,
+            memory: VecDeque::with_capacity(capacity),
+            capacity,
+            spilled: true,
+        })
+    }
+
+    /// Push a new entry into the queue, spilling the entries in memory to the WAL if it
+    /// exceeds its capacity.
+    ///
+    /// If the WAL can only take some of the entries in memory the others stay in memory, the
+    /// new entry is only kept if that leaves room for it. An entry that is rejected is not
+    /// enqueued, so the push can be retried.
+    ///
+    /// Once the entries are written to the WAL the push succeeds even if syncing them fails,
+    /// the sync is retried by the next spill and its failure is reported by
+    /// [`SpillQueue::flush`] and [`SpillQueue::close`].
+    ///
+    /// ## Errors
+    /// On IO Errors while writing or if the entry fits neither into memory nor into the WAL
+    pub async fn push<E>(&mut self, data: E) -> std::result::Result<(), Error<E::Error>>
+    where
+        E: Entry,
+    {
+        let data = data.serialize().map_err(Error::Entry)?;
+        self.memory.push_back(Record::new(data));
+        if self.memory.len() <= self.capacity {
+            return Ok(());
+        }
+        match self.write().await {
+            Ok(()) => {}
+            Err(Error::SizeExceeded) if self.memory.len() <= self.capacity => {}
+            Err(e) => {
+                // the new entry is the last one to be written, so it is still in memory
+                self.memory.pop_back();
+                return Err(match_error(e));
+            }
+        }
+        // the new entry is enqueued in the WAL or in memory, so a failing sync does not fail
+        // the push, it is retried by the next sync
+        let _synced = self.wal.sync_if_due().await;
+        Ok(())
+    }
+
+    /// Pop the oldest entry from the queue, returns `None` if the queue is empty.
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop<E>(&mut self) -> Result<Option<E::Output>>
+    where
+        E: Entry,
+    {
+        let data = match self.pop_spilled().await? {
+            Some(data) => data,
+            None => match self.memory.pop_front() {
+                Some(record) => record.data,
+                None => return Ok(None),
+            },
+        };
+        let entry = E::deserialize(data).map_err(Error::<E::Error>::Entry);
+        entry.map(Some).map_err(match_error)
+    }
+
+    /// Pops the oldest spilled entry from the WAL
+    async fn pop_spilled(&mut self) -> Result<Option<Vec<u8>>> {
+        if !self.spilled {
+            return Ok(None);
+        }
+        if let Some((idx, data)) = self.wal.pop::<Vec<u8>>().await? {
+            self.wal.ack(idx).await?;
+            Ok(Some(data))
+        } else {
+            self.spilled = false;
+            Ok(None)
+        }
+    }
+
+    /// Number of entries kept in memory
+    #[must_use]
+    pub fn in_memory(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Spills the entries in memory to the WAL and syncs it, afterwards all entries in the
+    /// queue are durable.
+    ///
+    /// ## Errors
+    /// On IO Errors or if spilling exceeds the WAL's capacity
+    pub async fn flush(&mut self) -> Result<()> {
+        self.spill().await?;
+        self.wal.flush().await
+    }
+
+    /// Spills the entries in memory to the WAL and closes it, see [`Wal::close`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if spilling exceeds the WAL's capacity
+    pub async fn close(mut self) -> Result<()> {
+        self.flush().await?;
+        self.wal.close().await
+    }
+
+    /// Spills the entries in memory to the WAL
+    async fn spill(&mut self) -> Result<()> {
+        self.write().await?;
+        self.wal.sync_if_due().await
+    }
+
+    /// Writes the entries in memory to the WAL without syncing it
+    async fn write(&mut self) -> Result<()> {
+        if self.memory.is_empty() {
+            return Ok(());
+        }
+        self.spilled = true;
+        // entries are only taken from memory as they are written, so nothing is lost if the
+        // WAL exceeds its limits
+        self.wal.write_batch(&mut self.memory, true).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(all(target_os = "linux", feature = "async-std"))]
+    use async_std::fs::OpenOptions;
+    use tempfile::Builder as TempDirBuilder;
+    #[cfg(all(target_os = "linux", feature = "tokio"))]
+    use tokio::fs::OpenOptions;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn spill() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        {
+            let mut q = SpillQueue::open(&path, 2, Options::new(1024, 10)).await?;
+            q.push(b"1".to_vec()).await?;
+            q.push(b"2".to_vec()).await?;
+            assert_eq!(q.in_memory(), 2);
+            assert_eq!(q.wal.write_file.next_idx_to_write, 1);
+            // exceeding the capacity spills everything in memory
+            q.push(b"3".to_vec()).await?;
+            assert_eq!(q.in_memory(), 0);
+            assert_eq!(q.wal.durable_idx(), 3);
+            q.push(b"4".to_vec()).await?;
+
+            assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"1".to_vec()));
+            assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"2".to_vec()));
+            q.push(b"5".to_vec()).await?;
+            assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"3".to_vec()));
+            assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"4".to_vec()));
+            assert_eq!(q.in_memory(), 1);
+            q.push(b"6".to_vec()).await?;
+            q.close().await?;
+        }
+        let mut q = SpillQueue::open(&path, 2, Options::new(1024, 10)).await?;
+        q.push(b"7".to_vec()).await?;
+        assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"5".to_vec()));
+        assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"6".to_vec()));
+        assert_eq!(q.pop::<Vec<u8>>().await?, Some(b"7".to_vec()));
+        assert_eq!(q.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn full() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut q = SpillQueue::open(&path, 2, Options::new(128, 1)).await?;
+        let mut pushed = Vec::new();
+        for i in 0..20u8 {
+            match q.push(vec![i; 16]).await {
+                Ok(()) => pushed.push(vec![i; 16]),
+                Err(Error::SizeExceeded) => (),
+                Err(e) => return Err(e),
+            }
+            assert!(q.in_memory() <= 2);
+        }
+        assert!(pushed.len() < 20);
+        // rejected entries are not enqueued
+        let mut popped = Vec::new();
+        while let Some(entry) = q.pop::<Vec<u8>>().await? {
+            popped.push(entry);
+        }
+        assert_eq!(popped, pushed);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn sync_failure() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let mut q = SpillQueue::open(&path, 1, Options::new(1024, 10)).await?;
+        // writes to /dev/null succeed but it can not be synced
+        q.wal.write_file.file = OpenOptions::new().write(true).open("/dev/null").await?;
+        q.push(b"1".to_vec()).await?;
+        // the entries are written, so the push succeeds although the sync failed
+        q.push(b"2".to_vec()).await?;
+        assert_eq!(q.in_memory(), 0);
+        assert_eq!(q.wal.write_file.next_idx_to_write, 3);
+        assert_eq!(q.wal.durable_idx(), 0);
+        assert!(matches!(q.flush().await, Err(Error::Io(_))));
+        Ok(())
+    }
+}
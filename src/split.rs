@@ -0,0 +1,307 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Entry, Envelope, Error, Headers, Result, SharedWal, Wal, WalSink, WalStream};
+use std::{ops::Range, time::Duration};
+
+/// The producing half of a [`Wal`], created by [`Wal::split`].
+///
+/// Producers can be cloned to push from multiple tasks, see [`SharedWal`] for how concurrent
+/// pushes are synced.
+#[derive(Clone)]
+pub struct WalProducer {
+    pub(crate) wal: SharedWal,
+}
+
+/// The consuming half of a [`Wal`], created by [`Wal::split`].
+///
+/// There is only one consumer for a WAL as there is only one read and ack index.
+pub struct WalConsumer {
+    pub(crate) wal: SharedWal,
+}
+
+impl Wal {
+    /// Splits the WAL into a producer that pushes entries and a consumer that pops and
+    /// acknowledges them, so both can be used from different tasks.
+    ///
+    /// This is a convenience wrapper over [`SharedWal`]: both halves are handles to the same
+    /// shared WAL and only expose its producing or consuming side. Pushes and pops are
+    /// serialized by its lock like they are for a [`SharedWal`], so a push waits for a pop in
+    /// progress and vice versa. Waiting for syncs or new entries happens without the lock.
+    #[must_use]
+    pub fn split(self) -> (WalProducer, WalConsumer) {
+        let wal = self.into_shared();
+        (WalProducer { wal: wal.clone() }, WalConsumer { wal })
+    }
+}
+
+impl WalProducer {
+    /// Push a new entry into the write-ahead-log, see [`SharedWal::push`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push<E>(&self, data: E) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        self.wal.push(data).await
+    }
+
+    /// Push a new entry into the write-ahead-log that expires after `ttl`, see
+    /// [`Wal::push_with_ttl`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push_with_ttl<E>(
+        &self,
+        data: E,
+        ttl: Duration,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        self.wal.push_with_ttl(data, ttl).await
+    }
+
+    /// Push a new entry with `headers` into the write-ahead-log, see
+    /// [`Wal::push_with_headers`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entry is exceed the WAL's capacity
+    pub async fn push_with_headers<E>(
+        &self,
+        data: E,
+        headers: Headers,
+    ) -> std::result::Result<u64, Error<E::Error>>
+    where
+        E: Entry,
+    {
+        self.wal.push_with_headers(data, headers).await
+    }
+
+    /// Push a batch of entries into the write-ahead-log, see [`Wal::push_batch`].
+    ///
+    /// ## Errors
+    /// On IO Errors or if the entries exceed the WAL's capacity
+    pub async fn push_batch<E, I>(
+        &self,
+        entries: I,
+    ) -> std::result::Result<Range<u64>, Error<E::Error>>
+    where
+        E: Entry,
+        I: IntoIterator<Item = E>,
+    {
+        self.wal.push_batch(entries).await
+    }
+
+    /// Syncs all entries written so far to disk, see [`Wal::flush`].
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn flush(&self) -> Result<()> {
+        self.wal.flush().await
+    }
+
+    /// The highest index that is synced to disk
+    #[must_use]
+    pub fn durable_idx(&self) -> u64 {
+        self.wal.durable_idx()
+    }
+
+    /// Number of entries dropped by the overflow policy, see [`Wal::dropped`].
+    pub async fn dropped(&self) -> u64 {
+        self.wal.dropped().await
+    }
+
+    /// A sink pushing entries into the write-ahead-log, see [`SharedWal::sink`].
+    #[must_use]
+    pub fn sink<E>(&self, capacity: usize) -> WalSink<E>
+    where
+        E: Entry,
+    {
+        self.wal.sink(capacity)
+    }
+}
+
+impl WalConsumer {
+    /// Pop an existing entry from the write-ahead-log, see [`Wal::pop`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop<E>(&mut self) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.pop::<E>().await
+    }
+
+    /// Returns the next entry without popping it, see [`Wal::peek`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn peek<E>(&mut self) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.peek::<E>().await
+    }
+
+    /// Returns up to `n` upcoming entries without popping them, see [`Wal::peek_n`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn peek_n<E>(&mut self, n: usize) -> Result<Vec<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.peek_n::<E>(n).await
+    }
+
+    /// Pop an existing entry with its metadata from the write-ahead-log, see
+    /// [`Wal::pop_envelope`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_envelope<E>(&mut self) -> Result<Option<Envelope<E::Output>>>
+    where
+        E: Entry,
+    {
+        self.wal.pop_envelope::<E>().await
+    }
+
+    /// Pop an entry from the write-ahead-log, waiting for one to be pushed if the WAL is
+    /// empty, see [`SharedWal::pop_wait`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_wait<E>(&mut self) -> Result<(u64, E::Output)>
+    where
+        E: Entry,
+    {
+        self.wal.pop_wait::<E>().await
+    }
+
+    /// Pop an entry from the write-ahead-log, waiting up to `duration` for one to be pushed,
+    /// see [`SharedWal::pop_timeout`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_timeout<E>(&mut self, duration: Duration) -> Result<Option<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.pop_timeout::<E>(duration).await
+    }
+
+    /// Pop up to `max_entries` existing entries from the write-ahead-log, see
+    /// [`Wal::pop_batch`].
+    ///
+    /// ## Errors
+    /// Erros on IO Errors or invalid WAL files
+    pub async fn pop_batch<E>(
+        &mut self,
+        max_entries: usize,
+        max_bytes: u64,
+    ) -> Result<Vec<(u64, E::Output)>>
+    where
+        E: Entry,
+    {
+        self.wal.pop_batch::<E>(max_entries, max_bytes).await
+    }
+
+    /// Acknowledges an entry as completely processed, see [`Wal::ack`].
+    ///
+    /// ## Errors
+    /// see [`Wal::ack`]
+    pub async fn ack(&mut self, id: u64) -> Result<()> {
+        self.wal.ack(id).await
+    }
+
+    /// Reverts the read index back to the last acknowledged index, see [`Wal::revert`].
+    ///
+    /// ## Errors
+    /// on IO Errors or invalid WAL files
+    pub async fn revert(&mut self) -> Result<()> {
+        self.wal.revert().await
+    }
+
+    /// Turns the consumer into a stream of the entries popped from the write-ahead-log, see
+    /// [`SharedWal::stream`]. Entries are acknowledged through [`WalStream::wal`].
+    #[must_use]
+    pub fn into_stream<E>(self) -> WalStream<E>
+    where
+        E: Entry + 'static,
+        E::Output: Send,
+    {
+        self.wal.stream()
+    }
+
+    /// Persists the ack index, see [`Wal::preserve_ack`].
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn preserve_ack(&mut self) -> Result<()> {
+        self.wal.lock().await.preserve_ack().await
+    }
+
+    /// Persists the ack index, see [`Wal::close`].
+    ///
+    /// ## Errors
+    /// On IO Errors
+    pub async fn close(self) -> Result<()> {
+        self.wal.close().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::spawn;
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test(flavor = "multi_thread"))]
+    async fn split() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        // small chunks so the consumer follows the producer across chunks
+        let (producer, mut consumer) = Wal::open(&path, 256, 100).await?.split();
+        let consumer = spawn(async move {
+            for idx in 1..=100 {
+                let (popped, data) = consumer.pop_wait::<Vec<u8>>().await?;
+                assert_eq!(popped, idx);
+                assert_eq!(data, idx.to_be_bytes().to_vec());
+                if idx % 10 == 0 {
+                    consumer.ack(idx).await?;
+                }
+            }
+            Ok::<_, Error<_>>(consumer)
+        });
+        let producer = spawn(async move {
+            for idx in 1..=100u64 {
+                assert_eq!(producer.push(idx.to_be_bytes().to_vec()).await?, idx);
+            }
+            Ok::<_, Error<_>>(producer)
+        });
+        let producer = producer.await?;
+        let consumer = consumer.await?;
+        consumer.close().await?;
+        drop(producer);
+
+        let mut w = Wal::open(&path, 256, 100).await?;
+        assert_eq!(w.pop::<Vec<u8>>().await?, None);
+        Ok(())
+    }
+}
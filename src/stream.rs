@@ -0,0 +1,110 @@
+// Copyright 2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Entry, Result, SharedWal};
+use futures_core::Stream;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+type Pop<E> = Pin<Box<dyn Future<Output = Result<(u64, <E as Entry>::Output)>> + Send>>;
+
+/// A [`Stream`] of the entries popped from a [`SharedWal`], created by [`SharedWal::stream`].
+///
+/// The stream waits for new entries when the WAL is empty and never ends. Entries still have
+/// to be acknowledged through the [`SharedWal`].
+pub struct WalStream<E: Entry> {
+    wal: SharedWal,
+    pop: Option<Pop<E>>,
+}
+
+impl SharedWal {
+    /// A stream of the entries popped from the write-ahead-log, see [`SharedWal::pop_wait`]
+    #[must_use]
+    pub fn stream<E>(&self) -> WalStream<E>
+    where
+        E: Entry + 'static,
+        E::Output: Send,
+    {
+        WalStream {
+            wal: self.clone(),
+            pop: None,
+        }
+    }
+}
+
+impl<E> WalStream<E>
+where
+    E: Entry,
+{
+    /// The WAL the entries are popped from
+    #[must_use]
+    pub fn wal(&self) -> &SharedWal {
+        &self.wal
+    }
+}
+
+impl<E> Stream for WalStream<E>
+where
+    E: Entry + 'static,
+    E::Output: Send,
+{
+    type Item = Result<(u64, E::Output)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let pop = this.pop.get_or_insert_with(|| {
+            let wal = this.wal.clone();
+            Box::pin(async move { wal.pop_wait::<E>().await })
+        });
+        let entry = ready!(pop.as_mut().poll(cx));
+        this.pop = None;
+        Poll::Ready(Some(entry))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Wal;
+    use futures::StreamExt;
+    use tempfile::Builder as TempDirBuilder;
+
+    #[cfg_attr(feature = "async-std", async_std::test)]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn stream() -> Result<()> {
+        let temp_dir = TempDirBuilder::new().prefix("tremor-wal").tempdir()?;
+        let path = temp_dir.path().to_path_buf();
+
+        let wal = Wal::open(&path, 512, 100).await?.into_shared();
+        for i in 0..3 {
+            wal.push(vec![i]).await?;
+        }
+        let mut stream = wal.stream::<Vec<u8>>();
+        let popped: Vec<_> = stream.by_ref().take(2).collect().await;
+        assert_eq!(popped.len(), 2);
+        assert_eq!(popped[0].as_ref().ok(), Some(&(1, vec![0])));
+        assert_eq!(popped[1].as_ref().ok(), Some(&(2, vec![1])));
+        stream.wal().ack(2).await?;
+
+        assert_eq!(stream.next().await.transpose()?, Some((3, vec![2])));
+        // the stream is polled first and waits for the entry pushed after it
+        let (next, pushed) = futures::join!(stream.next(), wal.push(vec![3]));
+        assert_eq!(pushed?, 4);
+        assert_eq!(next.transpose()?, Some((4, vec![3])));
+        Ok(())
+    }
+}